@@ -0,0 +1,460 @@
+//! Static checks that look at a program without running it.
+
+use crate::{labels, Op};
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// A problem found by `check`, at the index of the instruction concerned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    NonContiguousLabel { at: usize, label: usize }, // the first label id leaving a gap
+    UndefinedLabel { at: usize, label: i64 },       // a jump or switch to an undefined label
+    DuplicateLabel { at: usize, label: usize },     // a second definition of a label
+    BadJump { at: usize },                          // a relative jump out of the program
+    StackUnderflow { at: usize },                   // the op may run with too few values to pop
+    UnbalancedStack { at: usize },                  // paths reach here with different depths
+    Unreachable { at: usize },                      // the first of a run of dead instructions
+    DeadStore { at: usize, address: usize },        // a store to an address nothing loads from
+}
+
+impl Diagnostic {
+    /// Whether this only points at likely mistakes in a program that runs
+    /// fine, rather than at something that fails or is malformed.
+    pub fn is_warning(&self) -> bool {
+        matches!(self, Diagnostic::DeadStore { .. })
+    }
+}
+
+/// A run of instructions that control only enters at the start of and only
+/// leaves at the end of, see `basic_blocks`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub range: Range<usize>,    // the instructions, starting at `range.start`
+    pub successors: Vec<usize>, // indices of the blocks control can go to next
+}
+
+// Stack depth on entry to an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Depth {
+    Known(usize),
+    Unknown, // after an op with a runtime dependent effect, or a trap handler
+}
+
+/// Checks the structure of `program` without executing any of it.
+///
+/// Jump targets are only known when the label or offset is the literal
+/// right before the jump; any other jump is assumed to be able to reach
+/// every label, or every instruction for relative jumps. Stack depths are
+/// followed along every path from the entry point, and stop being checked
+/// after ops whose effect depends on the input or the stack, such as
+/// `Read` or `DupAll`.
+///
+/// Stores to a literal address that no `Load` from a literal address reads
+/// are reported as dead. Any other op that reads memory, such as a `Load`
+/// from a computed address or a `MemCopy`, could read any address, so then
+/// no store is reported. Memory the host maps or reads after the run is not
+/// known either, so a dead store is only a warning.
+pub fn check(program: &[Op]) -> Result<(), Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    let depths = depths(program, &mut diagnostics);
+
+    let mut in_run = false;
+    for (at, op) in program.iter().enumerate() {
+        if depths[at].is_some() {
+            in_run = false;
+        } else if !in_run && !matches!(op, Op::Label(_) | Op::Start) {
+            diagnostics.push(Diagnostic::Unreachable { at });
+            in_run = true;
+        }
+    }
+    dead_stores(program, &mut diagnostics);
+
+    if diagnostics.is_empty() {
+        Ok(())
+    } else {
+        Err(diagnostics)
+    }
+}
+
+/// An upper bound on how many values the stack of `program` can hold, or
+/// None when it cannot be told statically: when some path could underflow,
+/// loops with a growing stack, or reaches an op whose effect depends on the
+/// input or the stack, or a trap handler. Jumps are followed as in `check`.
+pub fn max_stack_depth(program: &[Op]) -> Option<usize> {
+    let depths = depths(program, &mut Vec::new());
+    let mut max = 0;
+    for (op, depth) in program.iter().zip(depths) {
+        match depth {
+            Some(Depth::Known(depth)) => {
+                let (pops, pushes) = op.stack_effect()?;
+                max = max.max(depth.checked_sub(pops)? + pushes);
+                max = max.max(depth);
+            }
+            Some(Depth::Unknown) => return None,
+            None => (),
+        }
+    }
+    Some(max)
+}
+
+/// Splits `program` into basic blocks, in program order. A block starts at
+/// the entry point, at every label and after every jump, and jumps are
+/// followed as in `check`. Finishing the program is not a successor, and
+/// neither are trap handlers, which are entered on errors anywhere after the
+/// `Trap`.
+pub fn basic_blocks(program: &[Op]) -> Vec<Block> {
+    let (successors, _) = successors(program, &mut Vec::new());
+    let mut leaders = vec![false; program.len() + 1];
+    leaders[0] = true;
+    for (at, op) in program.iter().enumerate() {
+        if matches!(op, Op::Label(_) | Op::Start) {
+            leaders[at] = true;
+        }
+        if successors[at] != [at + 1] {
+            leaders[at + 1] = true;
+            for &next in &successors[at] {
+                leaders[next] = true;
+            }
+        }
+    }
+
+    let starts: Vec<usize> = (0..program.len()).filter(|&at| leaders[at]).collect();
+    let mut blocks = Vec::with_capacity(starts.len());
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(program.len());
+        let mut next: Vec<usize> = successors[end - 1]
+            .iter()
+            .filter_map(|next| starts.binary_search(next).ok())
+            .collect();
+        next.sort_unstable();
+        next.dedup();
+        blocks.push(Block {
+            range: start..end,
+            successors: next,
+        });
+    }
+    blocks
+}
+
+// Reports stores to literal addresses that are never loaded, unless memory
+// is read in a way that could reach any address
+fn dead_stores(program: &[Op], diagnostics: &mut Vec<Diagnostic>) {
+    let mut loaded = Vec::new();
+    let mut stores = Vec::new();
+    for (at, op) in program.iter().enumerate() {
+        let address = match at.checked_sub(1).map(|i| &program[i]) {
+            Some(Op::Lit(x)) => usize::try_from(*x).ok(),
+            _ => None,
+        };
+        match (op, address) {
+            (Op::Load, Some(address)) => loaded.push(address),
+            (Op::Store, Some(address)) => stores.push((at, address)),
+            (Op::Store, None) => (),
+            (
+                Op::Load
+                | Op::LoadByte
+                | Op::MemCopy
+                | Op::MemSwap
+                | Op::Cas
+                | Op::MemCount
+                | Op::MemSort
+                | Op::MemHash
+                | Op::MemEq
+                | Op::MemMax
+                | Op::MemMin
+                | Op::PutStrMem,
+                _,
+            ) => return,
+            _ => (),
+        }
+    }
+    for (at, address) in stores {
+        if !loaded.contains(&address) {
+            diagnostics.push(Diagnostic::DeadStore { at, address });
+        }
+    }
+}
+
+// The stack depth on entry to each instruction, or None for instructions
+// that are never reached
+fn depths(program: &[Op], diagnostics: &mut Vec<Diagnostic>) -> Vec<Option<Depth>> {
+    let labels = labels(program);
+
+    // Label ids have to be unique and contiguous from zero, see `VM::prepare`
+    let mut defined = BTreeSet::new();
+    for (at, op) in program.iter().enumerate() {
+        if let Op::Label(label) = op {
+            if !defined.insert(*label) {
+                diagnostics.push(Diagnostic::DuplicateLabel { at, label: *label });
+            }
+        }
+    }
+    for (expected, (&label, &at)) in labels.iter().enumerate() {
+        if label != expected {
+            diagnostics.push(Diagnostic::NonContiguousLabel { at, label });
+            break;
+        }
+    }
+
+    let (successors, handlers) = successors(program, diagnostics);
+
+    // Follow the stack depth along every path from the entry point
+    let mut depths = vec![None; program.len()];
+    let mut work = Vec::new();
+    let entry = program.iter().position(|op| *op == Op::Start).unwrap_or(0);
+    reach(entry, Depth::Known(0), &mut depths, &mut work, diagnostics);
+    while let Some(at) = work.pop() {
+        let depth = match (depths[at], program[at].stack_effect()) {
+            (Some(Depth::Known(depth)), Some((pops, _))) if pops > depth => {
+                diagnostics.push(Diagnostic::StackUnderflow { at });
+                Depth::Unknown
+            }
+            (Some(Depth::Known(depth)), Some((pops, pushes))) => {
+                Depth::Known(depth - pops + pushes)
+            }
+            _ => Depth::Unknown,
+        };
+        for &next in &successors[at] {
+            reach(next, depth, &mut depths, &mut work, diagnostics);
+        }
+        // The handler starts with the error code pushed onto whatever the
+        // stack held when the error happened
+        for &handler in &handlers[at] {
+            reach(handler, Depth::Unknown, &mut depths, &mut work, diagnostics);
+        }
+    }
+    depths
+}
+
+// Where control can go after each instruction, and where trap handlers
+// registered by each instruction start. Indices past the end of the program
+// are where it finishes
+fn successors(
+    program: &[Op],
+    diagnostics: &mut Vec<Diagnostic>,
+) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+    let labels = labels(program);
+    let mut successors = Vec::with_capacity(program.len());
+    let mut handlers = Vec::with_capacity(program.len());
+    for (at, op) in program.iter().enumerate() {
+        let literal = match at.checked_sub(1).map(|i| &program[i]) {
+            Some(Op::Lit(x)) => Some(*x),
+            _ => None,
+        };
+        let mut label_targets = |label: Option<i64>| match label {
+            Some(label) => {
+                let target = usize::try_from(label).ok().and_then(|l| labels.get(&l));
+                if target.is_none() {
+                    diagnostics.push(Diagnostic::UndefinedLabel { at, label });
+                }
+                target.into_iter().copied().collect()
+            }
+            None => labels.values().copied().collect(),
+        };
+        let mut next = vec![at + 1];
+        let mut handler = Vec::new();
+        match op {
+            Op::Jmp => next = label_targets(literal),
+            Op::CJmp | Op::JmpEq | Op::JmpNe | Op::JmpLt | Op::JmpGt => {
+                next.extend(label_targets(literal))
+            }
+            Op::JmpNeg | Op::JmpPos | Op::JmpZero => next.extend(label_targets(literal)),
+            Op::Trap => handler = label_targets(literal),
+            Op::Switch(targets) => {
+                for &label in targets {
+                    next.extend(label_targets(Some(label as i64)));
+                }
+            }
+            Op::RJmp | Op::RCJmp => {
+                if *op == Op::RJmp {
+                    next.clear();
+                }
+                match literal {
+                    Some(offset) => {
+                        let target = (at as i64)
+                            .checked_add(offset)
+                            .and_then(|target| usize::try_from(target).ok())
+                            .filter(|target| *target <= program.len());
+                        match target {
+                            Some(target) => next.push(target),
+                            None => diagnostics.push(Diagnostic::BadJump { at }),
+                        }
+                    }
+                    None => next.extend(0..program.len()),
+                }
+            }
+            Op::Halt | Op::Exit => next.clear(),
+            _ => (),
+        }
+        successors.push(next);
+        handlers.push(handler);
+    }
+    (successors, handlers)
+}
+
+// Merges the depth some path reaches `at` with into what is known about it,
+// queueing `at` to be looked at again when that changed
+fn reach(
+    at: usize,
+    depth: Depth,
+    depths: &mut [Option<Depth>],
+    work: &mut Vec<usize>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let Some(old) = depths.get(at).copied() else {
+        return;
+    };
+    let new = match (old, depth) {
+        (None, depth) => depth,
+        (Some(old), depth) if old == depth => return,
+        (Some(Depth::Unknown), _) => return,
+        (Some(Depth::Known(_)), Depth::Known(_)) => {
+            diagnostics.push(Diagnostic::UnbalancedStack { at });
+            Depth::Unknown
+        }
+        (Some(Depth::Known(_)), Depth::Unknown) => Depth::Unknown,
+    };
+    depths[at] = Some(new);
+    work.push(at);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lexer;
+
+    fn check_source(source: &str) -> Result<(), Vec<Diagnostic>> {
+        check(&Lexer::codegen(source).unwrap())
+    }
+
+    #[test]
+    fn well_formed() {
+        assert_eq!(check_source("10 0: 1- dup 0 cjmp"), Ok(()));
+        // A computed jump may go to any label
+        assert_eq!(check_source("0: 1 0 + jmp"), Ok(()));
+        // The handler runs with an unknown stack
+        assert_eq!(check_source("0 trap 1 0 / halt 0: . ."), Ok(()));
+        assert_eq!(check_source(""), Ok(()));
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(
+            check_source("1 jmp"),
+            Err(vec![Diagnostic::UndefinedLabel { at: 1, label: 1 }])
+        );
+        assert_eq!(
+            check_source("0 switch[0,2] 0:"),
+            Err(vec![Diagnostic::UndefinedLabel { at: 1, label: 2 }])
+        );
+        assert_eq!(
+            check_source("0: 2:"),
+            Err(vec![Diagnostic::NonContiguousLabel { at: 1, label: 2 }])
+        );
+        assert_eq!(
+            check_source("0: 1: 0:"),
+            Err(vec![Diagnostic::DuplicateLabel { at: 2, label: 0 }])
+        );
+    }
+
+    #[test]
+    fn bad_jump() {
+        assert_eq!(
+            check_source("1 -3 rcjmp"),
+            Err(vec![Diagnostic::BadJump { at: 2 }])
+        );
+        assert_eq!(check_source("1 rjmp 5"), Ok(()));
+    }
+
+    #[test]
+    fn stack() {
+        assert_eq!(
+            check_source("1 +"),
+            Err(vec![Diagnostic::StackUnderflow { at: 1 }])
+        );
+        // The jump skips pushing 5, so the label is reached with one or two
+        // values on the stack
+        assert_eq!(
+            check_source("7 1 0 cjmp 5 0: ."),
+            Err(vec![Diagnostic::UnbalancedStack { at: 5 }])
+        );
+        // Nothing is known after reading
+        assert_eq!(check_source(", + + +"), Ok(()));
+    }
+
+    #[test]
+    fn unreachable() {
+        assert_eq!(
+            check_source("halt 1 2 0: 3"),
+            Err(vec![Diagnostic::Unreachable { at: 1 }])
+        );
+        assert_eq!(
+            check_source("0 jmp 1 0: 2 halt 3 4"),
+            Err(vec![
+                Diagnostic::Unreachable { at: 2 },
+                Diagnostic::Unreachable { at: 6 }
+            ])
+        );
+        assert_eq!(
+            check_source("1 start 2"),
+            Err(vec![Diagnostic::Unreachable { at: 0 }])
+        );
+    }
+
+    #[test]
+    fn dead_stores() {
+        assert_eq!(
+            check_source("1 0 store 2 1 store 0 load"),
+            Err(vec![Diagnostic::DeadStore { at: 5, address: 1 }])
+        );
+        assert!(Diagnostic::DeadStore { at: 5, address: 1 }.is_warning());
+        assert!(!Diagnostic::Unreachable { at: 5 }.is_warning());
+        // Stores to computed addresses are not reported
+        assert_eq!(check_source("1 0 1 + store 0 load"), Ok(()));
+        // A computed load or block op could read any address
+        assert_eq!(check_source("1 0 store 0 dup + load"), Ok(()));
+        assert_eq!(check_source("1 0 store 0 1 memsort"), Ok(()));
+    }
+
+    #[test]
+    fn basic_blocks() {
+        let blocks = |source| super::basic_blocks(&Lexer::codegen(source).unwrap());
+        let block = |range, successors: &[usize]| Block {
+            range,
+            successors: successors.to_vec(),
+        };
+        assert_eq!(blocks(""), []);
+        assert_eq!(blocks("1 2 +"), [block(0..3, &[])]);
+        // A loop back to its own start, then the code after it
+        assert_eq!(
+            blocks("10 0: 1- dup 0 cjmp . halt 5"),
+            [
+                block(0..1, &[1]),
+                block(1..6, &[1, 2]),
+                block(6..8, &[]),
+                block(8..9, &[]),
+            ]
+        );
+        // A relative jump starts a block at its target
+        assert_eq!(
+            blocks("3 rjmp 1 2 3"),
+            [block(0..2, &[2]), block(2..4, &[2]), block(4..5, &[])]
+        );
+    }
+
+    #[test]
+    fn max_stack_depth() {
+        let depth = |source| super::max_stack_depth(&Lexer::codegen(source).unwrap());
+        assert_eq!(depth(""), Some(0));
+        assert_eq!(depth("1 2 3 + 4 * dup"), Some(3));
+        assert_eq!(depth("1 2 3 4 halt 5 6 7"), Some(4));
+        // A loop that keeps the stack balanced
+        assert_eq!(depth("10 0: 1- dup 0 cjmp"), Some(3));
+        // A loop that grows the stack
+        assert_eq!(depth("0: 1 0 jmp"), None);
+        assert_eq!(depth("1 +"), None);
+        assert_eq!(depth("1 2 dupall"), None);
+    }
+}
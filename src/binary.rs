@@ -0,0 +1,536 @@
+//! A binary format for programs, for exchanging bytecode with other tools.
+//!
+//! A program is stored as:
+//!
+//! - the magic bytes `b"bytt"`
+//! - the format version, one byte, currently 1
+//! - the byte order of every following integer, one byte: 0 for little
+//!   endian and 1 for big endian
+//! - the number of ops, a u64
+//! - every op, as an opcode byte followed by its operand, if it has one
+//!
+//! Opcodes 0 to 6 are `Lit`, `Const`, `Label`, `Switch`, `Syscall`, `ToReg`
+//! and `FromReg`. A literal is an i64, a constant index, label id or syscall
+//! id is a u64, a register is a single byte, and a switch is a u64 count
+//! followed by that many u64 label ids. Ops without operands are numbered
+//! from 16 in the order of `SIMPLE`.
+
+use crate::Op;
+use alloc::vec::Vec;
+
+const MAGIC: &[u8; 4] = b"bytt";
+const VERSION: u8 = 1;
+
+// Ops without operands, in the order of their opcodes. New ops go at the
+// end so that existing opcodes keep their meaning
+const SIMPLE: &[Op] = &[
+    Op::Add,
+    Op::Sub,
+    Op::Mul,
+    Op::Div,
+    Op::FloorDiv,
+    Op::Sqrt,
+    Op::Gcd,
+    Op::PopCount,
+    Op::Clz,
+    Op::Ctz,
+    Op::RotL,
+    Op::RotR,
+    Op::Inc,
+    Op::Dec,
+    Op::SatAdd,
+    Op::SatSub,
+    Op::SatMul,
+    Op::MaxInt,
+    Op::MinInt,
+    Op::Load,
+    Op::Store,
+    Op::LoadByte,
+    Op::StoreByte,
+    Op::MemCopy,
+    Op::MemFill,
+    Op::MemSwap,
+    Op::MemCount,
+    Op::Start,
+    Op::Jmp,
+    Op::CJmp,
+    Op::JmpEq,
+    Op::JmpNe,
+    Op::JmpLt,
+    Op::JmpGt,
+    Op::RJmp,
+    Op::RCJmp,
+    Op::Put,
+    Op::Read,
+    Op::ReadAll,
+    Op::CR,
+    Op::Dup,
+    Op::Swap,
+    Op::Tuck,
+    Op::Nip,
+    Op::DupAll,
+    Op::Eq,
+    Op::Lt,
+    Op::Gt,
+    Op::Select,
+    Op::Dump,
+    Op::Halt,
+    Op::Exit,
+    Op::Trap,
+    Op::ProgLen,
+    Op::Here,
+    Op::JmpNeg,
+    Op::JmpPos,
+    Op::JmpZero,
+    Op::Divides,
+    Op::Arg,
+    Op::MemSort,
+    Op::DropN,
+    Op::Fact,
+    Op::IsPow2,
+    Op::ReverseN,
+    Op::DigitVal,
+    Op::MemHash,
+    Op::Parity,
+    Op::DecDup,
+    Op::PutCr,
+    Op::Clamp,
+    Op::PutStrMem,
+    Op::AbsDiff,
+    Op::ToDigits,
+    Op::MemEq,
+    Op::MemMax,
+    Op::MemMin,
+    Op::Cas,
+];
+const FIRST_SIMPLE: u8 = 16;
+
+/// The byte order integers are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    BadMagic,               // the input does not start with the magic bytes
+    UnsupportedVersion(u8), // a format version this loader does not know
+    BadEndian(u8),          // the byte order is neither 0 nor 1
+    BadOpcode(usize, u8),   // the op at the index has an unknown opcode
+    Truncated,              // the input ends in the middle of the program
+    TooLarge,               // more ops than the loader allows, or an operand over usize::MAX
+}
+
+impl core::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            LoadError::BadMagic => f.write_str("not a bytti program"),
+            LoadError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            LoadError::BadEndian(endian) => write!(f, "bad byte order {}", endian),
+            LoadError::BadOpcode(i, opcode) => {
+                write!(f, "op {}: unknown opcode {}", i, opcode)
+            }
+            LoadError::Truncated => f.write_str("truncated program"),
+            LoadError::TooLarge => f.write_str("program too large"),
+        }
+    }
+}
+
+impl core::error::Error for LoadError {}
+
+/// Serializes `program` in the format described in the module docs.
+pub fn save_program(program: &[Op], endian: Endian) -> Vec<u8> {
+    let u64_bytes = |x: u64| match endian {
+        Endian::Little => x.to_le_bytes(),
+        Endian::Big => x.to_be_bytes(),
+    };
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    bytes.push(endian as u8);
+    bytes.extend(u64_bytes(program.len() as u64));
+    for op in program {
+        match op {
+            Op::Lit(x) => {
+                bytes.push(0);
+                bytes.extend(u64_bytes(*x as u64));
+            }
+            Op::Const(index) => {
+                bytes.push(1);
+                bytes.extend(u64_bytes(*index as u64));
+            }
+            Op::Label(label) => {
+                bytes.push(2);
+                bytes.extend(u64_bytes(*label as u64));
+            }
+            Op::Switch(targets) => {
+                bytes.push(3);
+                bytes.extend(u64_bytes(targets.len() as u64));
+                for label in targets {
+                    bytes.extend(u64_bytes(*label as u64));
+                }
+            }
+            Op::Syscall(id) => {
+                bytes.push(4);
+                bytes.extend(u64_bytes(*id as u64));
+            }
+            Op::ToReg(reg) => bytes.extend([5, *reg]),
+            Op::FromReg(reg) => bytes.extend([6, *reg]),
+            op => {
+                let i = SIMPLE
+                    .iter()
+                    .position(|simple| simple == op)
+                    .expect("op without operand has an opcode");
+                bytes.push(FIRST_SIMPLE + i as u8);
+            }
+        }
+    }
+    bytes
+}
+
+/// Deserializes a program saved with `save_program`, in either byte order.
+pub fn load_program(bytes: &[u8]) -> Result<Vec<Op>, LoadError> {
+    load_program_with_limit(bytes, usize::MAX)
+}
+
+/// Like `load_program`, but fails with `LoadError::TooLarge` as soon as it
+/// gets to the op after the first `max_ops`, for input that cannot be
+/// trusted. The program grows as ops are decoded rather than being
+/// allocated up front for the count the input claims.
+pub fn load_program_with_limit(bytes: &[u8], max_ops: usize) -> Result<Vec<Op>, LoadError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(if MAGIC.starts_with(bytes) {
+            LoadError::Truncated
+        } else {
+            LoadError::BadMagic
+        });
+    }
+    let mut reader = Reader {
+        bytes: &bytes[MAGIC.len()..],
+        endian: Endian::Little,
+    };
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    reader.endian = match reader.u8()? {
+        0 => Endian::Little,
+        1 => Endian::Big,
+        endian => return Err(LoadError::BadEndian(endian)),
+    };
+
+    let len = reader.u64()?;
+    let mut program = Vec::new();
+    for i in 0..len {
+        if i >= max_ops as u64 {
+            return Err(LoadError::TooLarge);
+        }
+        let op = match reader.u8()? {
+            0 => Op::Lit(reader.u64()? as i64),
+            1 => Op::Const(reader.usize()?),
+            2 => Op::Label(reader.usize()?),
+            3 => {
+                let count = reader.u64()?;
+                let mut targets = Vec::new();
+                for _ in 0..count {
+                    targets.push(reader.usize()?);
+                }
+                Op::Switch(targets)
+            }
+            4 => Op::Syscall(reader.usize()?),
+            5 => Op::ToReg(reader.u8()?),
+            6 => Op::FromReg(reader.u8()?),
+            opcode => opcode
+                .checked_sub(FIRST_SIMPLE)
+                .and_then(|i| SIMPLE.get(i as usize))
+                .cloned()
+                .ok_or(LoadError::BadOpcode(i as usize, opcode))?,
+        };
+        program.push(op);
+    }
+    Ok(program)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    endian: Endian,
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        let (head, rest) = self.bytes.split_first_chunk().ok_or(LoadError::Truncated)?;
+        self.bytes = rest;
+        Ok(*head)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        let bytes = self.take()?;
+        Ok(match self.endian {
+            Endian::Little => u64::from_le_bytes(bytes),
+            Endian::Big => u64::from_be_bytes(bytes),
+        })
+    }
+
+    // A u64 that has to fit in a usize, which only fails on 32-bit targets
+    fn usize(&mut self) -> Result<usize, LoadError> {
+        usize::try_from(self.u64()?).map_err(|_| LoadError::TooLarge)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lexer;
+    use alloc::vec;
+
+    #[test]
+    fn round_trip() {
+        let program =
+            Lexer::codegen("start -5 $2 0: 1 switch[0,3] syscall[7] + >r2 r2> dupall here")
+                .unwrap();
+        for endian in [Endian::Little, Endian::Big] {
+            assert_eq!(
+                load_program(&save_program(&program, endian)),
+                Ok(program.clone())
+            );
+        }
+        for (i, op) in SIMPLE.iter().enumerate() {
+            let bytes = save_program(core::slice::from_ref(op), Endian::Little);
+            assert_eq!(bytes[bytes.len() - 1], FIRST_SIMPLE + i as u8);
+            assert_eq!(load_program(&bytes), Ok(vec![op.clone()]));
+        }
+    }
+
+    #[test]
+    fn byte_order() {
+        let little = save_program(&[Op::Lit(1)], Endian::Little);
+        let big = save_program(&[Op::Lit(1)], Endian::Big);
+        assert_eq!(little[..6], *b"bytt\x01\x00");
+        assert_eq!(little[6..14], 1u64.to_le_bytes());
+        assert_eq!(little[15..], 1u64.to_le_bytes());
+        assert_eq!(big[..6], *b"bytt\x01\x01");
+        assert_eq!(big[15..], 1u64.to_be_bytes());
+    }
+
+    #[test]
+    fn errors() {
+        let bytes = save_program(&[Op::Lit(1), Op::Add], Endian::Little);
+        assert_eq!(load_program(b"bytx\x01\x00"), Err(LoadError::BadMagic));
+        assert_eq!(load_program(b"byt"), Err(LoadError::Truncated));
+        assert_eq!(
+            load_program(b"bytt\x02\x00"),
+            Err(LoadError::UnsupportedVersion(2))
+        );
+        assert_eq!(load_program(b"bytt\x01\x02"), Err(LoadError::BadEndian(2)));
+        for len in 4..bytes.len() {
+            assert_eq!(load_program(&bytes[..len]), Err(LoadError::Truncated));
+        }
+
+        assert_eq!(
+            load_program_with_limit(&bytes, 2),
+            Ok(vec![Op::Lit(1), Op::Add])
+        );
+        assert_eq!(load_program_with_limit(&bytes, 1), Err(LoadError::TooLarge));
+        // A huge count is caught before the ops that are not there
+        let mut huge = bytes[..6].to_vec();
+        huge.extend(u64::MAX.to_le_bytes());
+        huge.extend(&bytes[14..]);
+        assert_eq!(load_program_with_limit(&huge, 2), Err(LoadError::TooLarge));
+        assert_eq!(load_program(&huge), Err(LoadError::Truncated));
+
+        let mut bad = bytes.clone();
+        *bad.last_mut().unwrap() = 255;
+        assert_eq!(load_program(&bad), Err(LoadError::BadOpcode(1, 255)));
+    }
+
+    #[test]
+    fn every_op_has_an_opcode() {
+        // One of each op. The match stops compiling when an op is added, as a
+        // reminder to list it here and give it an opcode
+        let ops = vec![
+            Op::Add,
+            Op::Sub,
+            Op::Mul,
+            Op::Div,
+            Op::FloorDiv,
+            Op::Sqrt,
+            Op::Fact,
+            Op::Gcd,
+            Op::AbsDiff,
+            Op::Divides,
+            Op::PopCount,
+            Op::Parity,
+            Op::Clz,
+            Op::Ctz,
+            Op::IsPow2,
+            Op::DigitVal,
+            Op::RotL,
+            Op::RotR,
+            Op::Inc,
+            Op::Dec,
+            Op::DecDup,
+            Op::SatAdd,
+            Op::SatSub,
+            Op::SatMul,
+            Op::Lit(-1),
+            Op::Const(2),
+            Op::MaxInt,
+            Op::MinInt,
+            Op::Load,
+            Op::Store,
+            Op::LoadByte,
+            Op::StoreByte,
+            Op::MemCopy,
+            Op::MemFill,
+            Op::MemSwap,
+            Op::MemCount,
+            Op::MemSort,
+            Op::MemHash,
+            Op::MemEq,
+            Op::MemMax,
+            Op::MemMin,
+            Op::ToDigits,
+            Op::Cas,
+            Op::Switch(vec![0, 1]),
+            Op::Label(3),
+            Op::Start,
+            Op::Jmp,
+            Op::CJmp,
+            Op::JmpEq,
+            Op::JmpNe,
+            Op::JmpLt,
+            Op::JmpGt,
+            Op::JmpNeg,
+            Op::JmpPos,
+            Op::JmpZero,
+            Op::RJmp,
+            Op::RCJmp,
+            Op::Put,
+            Op::Read,
+            Op::ReadAll,
+            Op::Arg,
+            Op::CR,
+            Op::PutCr,
+            Op::PutStrMem,
+            Op::Dup,
+            Op::Swap,
+            Op::Tuck,
+            Op::Nip,
+            Op::DupAll,
+            Op::DropN,
+            Op::ReverseN,
+            Op::ToReg(4),
+            Op::FromReg(5),
+            Op::Eq,
+            Op::Lt,
+            Op::Gt,
+            Op::Select,
+            Op::Clamp,
+            Op::Dump,
+            Op::Halt,
+            Op::Exit,
+            Op::Trap,
+            Op::ProgLen,
+            Op::Here,
+            Op::Syscall(6),
+        ];
+        for op in &ops {
+            match op {
+                Op::Add
+                | Op::Sub
+                | Op::Mul
+                | Op::Div
+                | Op::FloorDiv
+                | Op::Sqrt
+                | Op::Fact
+                | Op::Gcd
+                | Op::AbsDiff
+                | Op::Divides
+                | Op::PopCount
+                | Op::Parity
+                | Op::Clz
+                | Op::Ctz
+                | Op::IsPow2
+                | Op::DigitVal
+                | Op::RotL
+                | Op::RotR
+                | Op::Inc
+                | Op::Dec
+                | Op::DecDup
+                | Op::SatAdd
+                | Op::SatSub
+                | Op::SatMul
+                | Op::Lit(_)
+                | Op::Const(_)
+                | Op::MaxInt
+                | Op::MinInt
+                | Op::Load
+                | Op::Store
+                | Op::LoadByte
+                | Op::StoreByte
+                | Op::MemCopy
+                | Op::MemFill
+                | Op::MemSwap
+                | Op::MemCount
+                | Op::MemSort
+                | Op::MemHash
+                | Op::MemEq
+                | Op::MemMax
+                | Op::MemMin
+                | Op::ToDigits
+                | Op::Cas
+                | Op::Switch(_)
+                | Op::Label(_)
+                | Op::Start
+                | Op::Jmp
+                | Op::CJmp
+                | Op::JmpEq
+                | Op::JmpNe
+                | Op::JmpLt
+                | Op::JmpGt
+                | Op::JmpNeg
+                | Op::JmpPos
+                | Op::JmpZero
+                | Op::RJmp
+                | Op::RCJmp
+                | Op::Put
+                | Op::Read
+                | Op::ReadAll
+                | Op::Arg
+                | Op::CR
+                | Op::PutCr
+                | Op::PutStrMem
+                | Op::Dup
+                | Op::Swap
+                | Op::Tuck
+                | Op::Nip
+                | Op::DupAll
+                | Op::DropN
+                | Op::ReverseN
+                | Op::ToReg(_)
+                | Op::FromReg(_)
+                | Op::Eq
+                | Op::Lt
+                | Op::Gt
+                | Op::Select
+                | Op::Clamp
+                | Op::Dump
+                | Op::Halt
+                | Op::Exit
+                | Op::Trap
+                | Op::ProgLen
+                | Op::Here
+                | Op::Syscall(_) => (),
+            }
+        }
+        assert_eq!(ops.len(), SIMPLE.len() + 7);
+        let bytes = save_program(&ops, Endian::Big);
+        assert_eq!(load_program(&bytes), Ok(ops));
+    }
+}
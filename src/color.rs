@@ -0,0 +1,208 @@
+//! Listings of programs for the terminal, colored with ANSI escape codes,
+//! enabled by the `color` feature.
+//!
+//! Ops are colored by category: arithmetic in yellow, values in green,
+//! memory in cyan, control flow in magenta, I/O in blue and stack ops and
+//! the rest in the default color. Label definitions are bold.
+
+use crate::{symbolic, Op, Symbols};
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const REVERSE: &str = "\x1b[7m";
+const YELLOW: &str = "\x1b[33m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const MAGENTA: &str = "\x1b[35m";
+const BLUE: &str = "\x1b[34m";
+
+/// Whether output should be colored, which it is unless the `NO_COLOR`
+/// environment variable is set to something non-empty, see
+/// <https://no-color.org>.
+pub fn use_color() -> bool {
+    color_allowed(std::env::var_os("NO_COLOR"))
+}
+
+fn color_allowed(no_color: Option<std::ffi::OsString>) -> bool {
+    no_color.is_none_or(|value| value.is_empty())
+}
+
+/// Lists `program` with one op per line after its index.
+pub fn listing(program: &[Op], color: bool) -> String {
+    listing_at(program, None, &Symbols::default(), color)
+}
+
+/// Lists `program` like `listing`, but with the names in `symbols` for
+/// label definitions, for literal labels right before an op that jumps to
+/// or traps on them, and for literal addresses right before a `Load` or
+/// `Store`.
+pub fn listing_with_symbols(program: &[Op], symbols: &Symbols, color: bool) -> String {
+    listing_at(program, None, symbols, color)
+}
+
+/// Lists `program` like `listing`, marking the instruction at `ip`, the next
+/// one to execute, with an arrow and, when colored, reverse video.
+pub fn stepper(program: &[Op], ip: usize, color: bool) -> String {
+    listing_at(program, Some(ip), &Symbols::default(), color)
+}
+
+fn listing_at(program: &[Op], ip: Option<usize>, symbols: &Symbols, color: bool) -> String {
+    let mut out = String::new();
+    for (i, op) in program.iter().enumerate() {
+        let current = ip == Some(i);
+        out.push_str(if current { "> " } else { "  " });
+        let text = symbolic(op, program.get(i + 1), symbols).unwrap_or_else(|| op.to_string());
+        let line = format!("{:>4}  {}", i, text);
+        match (color, current) {
+            (false, _) => out.push_str(&line),
+            (true, true) => {
+                out.push_str(REVERSE);
+                out.push_str(&line);
+                out.push_str(RESET);
+            }
+            (true, false) => {
+                out.push_str(&format!("{:>4}  ", i));
+                out.push_str(style(op));
+                out.push_str(&text);
+                out.push_str(RESET);
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// The escape code an op is written in
+fn style(op: &Op) -> &'static str {
+    match op {
+        Op::Add
+        | Op::Sub
+        | Op::Mul
+        | Op::Div
+        | Op::FloorDiv
+        | Op::Sqrt
+        | Op::Fact
+        | Op::Gcd
+        | Op::AbsDiff
+        | Op::Divides
+        | Op::PopCount
+        | Op::Parity
+        | Op::Clz
+        | Op::Ctz
+        | Op::IsPow2
+        | Op::DigitVal
+        | Op::RotL
+        | Op::RotR
+        | Op::Inc
+        | Op::Dec
+        | Op::DecDup
+        | Op::SatAdd
+        | Op::SatSub
+        | Op::SatMul
+        | Op::Eq
+        | Op::Lt
+        | Op::Gt
+        | Op::Select
+        | Op::Clamp => YELLOW,
+        Op::Lit(_) | Op::Const(_) | Op::MaxInt | Op::MinInt | Op::ProgLen | Op::Here => GREEN,
+        Op::Load
+        | Op::Store
+        | Op::LoadByte
+        | Op::StoreByte
+        | Op::MemCopy
+        | Op::MemFill
+        | Op::MemSwap
+        | Op::Cas
+        | Op::MemCount
+        | Op::MemSort
+        | Op::MemHash
+        | Op::MemEq
+        | Op::MemMax
+        | Op::MemMin
+        | Op::ToDigits
+        | Op::ToReg(_)
+        | Op::FromReg(_) => CYAN,
+        Op::Label(_) => BOLD,
+        Op::Switch(_)
+        | Op::Start
+        | Op::Jmp
+        | Op::CJmp
+        | Op::JmpEq
+        | Op::JmpNe
+        | Op::JmpLt
+        | Op::JmpGt
+        | Op::JmpNeg
+        | Op::JmpPos
+        | Op::JmpZero
+        | Op::RJmp
+        | Op::RCJmp
+        | Op::Halt
+        | Op::Exit
+        | Op::Trap
+        | Op::Syscall(_) => MAGENTA,
+        Op::Put | Op::Read | Op::ReadAll | Op::Arg | Op::CR | Op::PutCr | Op::PutStrMem => BLUE,
+        Op::Dup
+        | Op::Swap
+        | Op::Tuck
+        | Op::Nip
+        | Op::DupAll
+        | Op::DropN
+        | Op::ReverseN
+        | Op::Dump => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lexer;
+
+    #[test]
+    fn colors() {
+        let program = Lexer::codegen("0: 2 dup + . 0 jmp").unwrap();
+        assert_eq!(
+            listing(&program, false),
+            "     0  0:\n     1  2\n     2  dup\n     3  +\n     4  .\n     5  0\n     6  jmp\n"
+        );
+        assert_eq!(
+            stepper(&program[..3], 1, false),
+            "     0  0:\n>    1  2\n     2  dup\n"
+        );
+
+        let colored = listing(&program, true);
+        assert!(colored.contains("   3  \x1b[33m+\x1b[0m\n"));
+        assert!(colored.contains("   6  \x1b[35mjmp\x1b[0m\n"));
+        assert!(colored.contains("   0  \x1b[1m0:\x1b[0m\n"));
+        assert_eq!(
+            stepper(&program[..2], 1, true),
+            "     0  \x1b[1m0:\x1b[0m\n> \x1b[7m   1  2\x1b[0m\n"
+        );
+        assert!(!listing(&program, false).contains('\x1b'));
+        assert!(!stepper(&program, 1, false).contains('\x1b'));
+    }
+
+    #[test]
+    fn symbols() {
+        let assembly = Lexer::assemble(
+            "#@name counter 3
+             #@name loop 0:
+             0: 3 load 1+ 3 store 3 0 jmp",
+        )
+        .unwrap();
+        let text = listing_with_symbols(&assembly.ops, &assembly.symbols, false);
+        assert_eq!(
+            text,
+            "     0  loop:\n     1  counter\n     2  load\n     3  1+\n     4  counter\n     \
+             5  store\n     6  3\n     7  loop\n     8  jmp\n"
+        );
+        let colored = listing_with_symbols(&assembly.ops, &assembly.symbols, true);
+        assert!(colored.contains("   1  \x1b[32mcounter\x1b[0m\n"));
+    }
+
+    #[test]
+    fn no_color() {
+        assert!(color_allowed(None));
+        assert!(color_allowed(Some("".into())));
+        assert!(!color_allowed(Some("1".into())));
+    }
+}
@@ -0,0 +1,4234 @@
+//! A small stack based virtual machine and the assembler for its language.
+//!
+//! Without the default `std` feature the crate is `no_std` and only needs
+//! `alloc`. The VM then has no stdin or stdout: `Op::Put`, `Op::CR`,
+//! `Op::PutCr`, `Op::PutStrMem` and `Op::Dump` write to the
+//! `core::fmt::Write` given to `VM::set_output` and fail with `VmError::Io`
+//! when there is none, and `Op::Read` and `Op::ReadAll` fail with
+//! `VmError::Io` unless they replay inputs given to `VM::replay_inputs`.
+//! Profiling, timed runs, `VM::dump`, `VM::dump_memory_region`,
+//! `opcode_histogram` and `run_io` are only available with `std`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::io::{BufRead, Read, Write};
+
+pub mod analysis;
+pub mod binary;
+#[cfg(feature = "color")]
+pub mod color;
+#[cfg(feature = "transpile")]
+pub mod transpile;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Add,          // pop two values, add them, and push the result
+    Sub,          // pop two values, subtract them, and push the result
+    Mul,          // pop two values, multiply them, and push the result
+    Div,          // pop two values, divide them, and push the result
+    FloorDiv,     // pop two values, divide them rounding down, and push the result
+    Sqrt,         // pop a non-negative value and push its integer square root
+    Fact,         // pop a non-negative value and push its factorial
+    Gcd,          // pop two values and push their non-negative greatest common divisor
+    AbsDiff,      // pop two values and push the absolute value of their difference
+    Divides,      // pop k and n and push one if k divides n, otherwise zero
+    PopCount,     // pop a value and push the number of set bits in it
+    Parity,       // pop a value and push one if it has an odd number of set bits
+    Clz,          // pop a value and push the number of leading zero bits, 64 for zero
+    Ctz,          // pop a value and push the number of trailing zero bits, 64 for zero
+    IsPow2,       // pop a value and push one if it is a positive power of two
+    DigitVal,     // pop an ASCII code and push its digit value, or -1 if not a digit
+    RotL,         // pop an amount and a value and rotate the value left by the amount mod 64
+    RotR,         // pop an amount and a value and rotate the value right by the amount mod 64
+    Inc,          // add one to the top value in place
+    Dec,          // subtract one from the top value in place
+    DecDup,       // subtract one from the top value and duplicate it, like `1- dup`
+    SatAdd,       // pop two values and push their sum clamped to the i64 range
+    SatSub,       // pop two values and push their difference clamped to the i64 range
+    SatMul,       // pop two values and push their product clamped to the i64 range
+    Lit(i64),     // push a literal onto the stack
+    Const(usize), // push a value from the constant pool
+    MaxInt,       // push the largest representable value
+    MinInt,       // push the smallest representable value
+    Load,         // load a value from memory and push it onto the stack
+    Store,        // pop a value and store it in memory
+    LoadByte,     // pop a byte address and push the byte stored there
+    StoreByte,    // pop a byte address and a value and store the low byte of the value there
+    MemCopy,      // pop a length, a source and a destination and copy the cells (overlap safe)
+    MemFill,      // pop a value, a length and a base and fill the cells with the value
+    MemSwap,      // pop two addresses and swap the values stored at them
+    MemCount,     // pop a value, a length and a base and push how many of the cells equal it
+    MemSort,      // pop a length and a base and sort the cells ascending (a stable sort)
+    MemHash,      // pop a length and a base and push the FNV-1a hash of the cells
+    MemEq,        // pop a length and two bases and push one if the two blocks are equal
+    MemMax,       // pop a length and a base and push the largest of the cells
+    MemMin,       // pop a length and a base and push the smallest of the cells
+    ToDigits,     // pop a value and a base, write its decimal digits there, push the count
+    Cas,          // pop new, expected and an address, store new if the cell is expected, push 1/0
+    // pop an index and jump to the label at that position in the list, or fall through
+    // when the index is out of range
+    Switch(Vec<usize>),
+    Label(usize), // create a label to jump to later
+    Start,        // mark the entry point, execution begins here instead of at the top
+    Jmp,          // unconditional jump to a label
+    CJmp,         // pop a value off the stack and jump if the value is non-zero
+    JmpEq,        // pop a label and two values and jump if a == b
+    JmpNe,        // pop a label and two values and jump if a != b
+    JmpLt,        // pop a label and two values and jump if a < b
+    JmpGt,        // pop a label and two values and jump if a > b
+    JmpNeg,       // pop a label and a value and jump if the value is negative
+    JmpPos,       // pop a label and a value and jump if the value is positive
+    JmpZero,      // pop a label and a value and jump if the value is zero
+    RJmp,         // pop an offset and jump that many instructions from this one
+    RCJmp,        // pop an offset and a value and jump relatively if the value is non-zero
+    Put,          // pop a value off the stack and write it to stdout
+    Read,         // read an integer and push it and a one, or only a zero at end of input
+    ReadAll,      // pop a base, read all input into memory a byte per cell, push the count
+    Arg,          // pop an index and push the program argument at that index
+    CR,           // write newline to stdout
+    PutCr,        // pop a value and write it and a newline, like `. cr`
+    PutStrMem,    // pop a base and write the cells from there up to a zero cell as characters
+    Dup,          // duplicate the top value onto the stack
+    Swap,         // swap the top two values on the stack
+    Tuck,         // insert a copy of the top value below the second value (a b -- b a b)
+    Nip,          // remove the second value (a b -- b)
+    DupAll,       // push a copy of the whole stack on top of it (a b -- a b a b)
+    DropN,        // pop a count and remove that many values
+    ReverseN,     // pop a count and reverse the order of that many values
+    ToReg(u8),    // pop a value into one of the 16 registers
+    FromReg(u8),  // push the value of one of the 16 registers
+    Eq,           // pop two values and put a one onto the stack if a == b, otherwise put zero
+    Lt,           // pop two values and put a one onto the stack if a < b, otherwise put zero
+    Gt,           // pop two values and put a one onto the stack if a > b, otherwise put zero
+    Select,       // pop b, a and a condition and push a if the condition is non-zero, otherwise b
+    Clamp,        // pop max, min and a value and push the value limited to min..=max
+    Dump,         // debug core dump
+    Halt,         // debug stop
+    Exit,         // pop an exit code and stop, returning the code instead of the top
+    Trap,         // pop a label to jump to, with an error code pushed, on catchable errors
+    ProgLen,      // push the number of instructions in the running program
+    Here,         // push the index of this instruction
+    // call the host function registered under this id, see `VM::register_syscall`
+    Syscall(usize),
+}
+
+impl Op {
+    /// The token this op is written as, or `lit` and `label` for literals and
+    /// label definitions.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::FloorDiv => "floordiv",
+            Op::Sqrt => "sqrt",
+            Op::Fact => "fact",
+            Op::MaxInt => "maxint",
+            Op::MinInt => "minint",
+            Op::Gcd => "gcd",
+            Op::AbsDiff => "absdiff",
+            Op::Divides => "divides",
+            Op::PopCount => "popcount",
+            Op::Parity => "parity",
+            Op::Clz => "clz",
+            Op::Ctz => "ctz",
+            Op::IsPow2 => "ispow2",
+            Op::DigitVal => "digitval",
+            Op::RotL => "rotl",
+            Op::RotR => "rotr",
+            Op::Inc => "1+",
+            Op::Dec => "1-",
+            Op::DecDup => "1-dup",
+            Op::SatAdd => "+s",
+            Op::SatSub => "-s",
+            Op::SatMul => "*s",
+            Op::Load => "load",
+            Op::Store => "store",
+            Op::LoadByte => "loadb",
+            Op::StoreByte => "storeb",
+            Op::MemCopy => "memcopy",
+            Op::MemFill => "memfill",
+            Op::MemSwap => "memswap",
+            Op::MemCount => "memcount",
+            Op::MemSort => "memsort",
+            Op::MemHash => "memhash",
+            Op::MemEq => "memeq",
+            Op::MemMax => "memmax",
+            Op::MemMin => "memmin",
+            Op::ToDigits => "todigits",
+            Op::Cas => "cas",
+            Op::Start => "start",
+            Op::Jmp => "jmp",
+            Op::CJmp => "cjmp",
+            Op::JmpEq => "jeq",
+            Op::JmpNe => "jne",
+            Op::JmpLt => "jlt",
+            Op::JmpGt => "jgt",
+            Op::JmpNeg => "jneg",
+            Op::JmpPos => "jpos",
+            Op::JmpZero => "jz",
+            Op::RJmp => "rjmp",
+            Op::RCJmp => "rcjmp",
+            Op::Put => ".",
+            Op::CR => "cr",
+            Op::PutCr => ".cr",
+            Op::PutStrMem => "putstr",
+            Op::Read => ",",
+            Op::ReadAll => "readall",
+            Op::Arg => "arg",
+            Op::Dup => "dup",
+            Op::Swap => "swap",
+            Op::Tuck => "tuck",
+            Op::Nip => "nip",
+            Op::DupAll => "dupall",
+            Op::DropN => "dropn",
+            Op::ReverseN => "reversen",
+            Op::Eq => "=",
+            Op::Lt => "<",
+            Op::Gt => ">",
+            Op::Select => "select",
+            Op::Clamp => "clamp",
+            Op::Dump => "?",
+            Op::Halt => "halt",
+            Op::Exit => "exit",
+            Op::Trap => "trap",
+            Op::ProgLen => "proglen",
+            Op::Here => "here",
+            Op::Lit(_) => "lit",
+            Op::Const(_) => "const",
+            Op::Label(_) => "label",
+            Op::Switch(_) => "switch",
+            Op::Syscall(_) => "syscall",
+            Op::ToReg(_) => ">r",
+            Op::FromReg(_) => "r>",
+        }
+    }
+
+    /// How many values this op pops and then pushes, or None when that
+    /// depends on the input or the stack at runtime.
+    pub fn stack_effect(&self) -> Option<(usize, usize)> {
+        let effect = match self {
+            Op::Lit(_) | Op::Const(_) | Op::MaxInt | Op::MinInt | Op::ProgLen | Op::Here => (0, 1),
+            Op::Label(_) | Op::Start | Op::CR | Op::Dump | Op::Halt => (0, 0),
+            Op::Sqrt | Op::Fact | Op::PopCount | Op::Clz | Op::Ctz | Op::IsPow2 => (1, 1),
+            Op::Inc | Op::Dec | Op::DigitVal | Op::Parity => (1, 1),
+            Op::Load | Op::LoadByte | Op::ReadAll | Op::Arg => (1, 1),
+            Op::Switch(_) | Op::Jmp | Op::RJmp | Op::Put | Op::Exit | Op::Trap => (1, 0),
+            Op::ToReg(_) | Op::PutCr | Op::PutStrMem => (1, 0),
+            Op::FromReg(_) => (0, 1),
+            Op::Dup | Op::DecDup => (1, 2),
+            Op::Add | Op::Sub | Op::Mul | Op::Div | Op::FloorDiv | Op::Gcd => (2, 1),
+            Op::RotL | Op::RotR | Op::SatAdd | Op::SatSub | Op::SatMul => (2, 1),
+            Op::Divides | Op::AbsDiff | Op::Nip | Op::Eq | Op::Lt | Op::Gt => (2, 1),
+            Op::MemHash | Op::MemMax | Op::MemMin | Op::ToDigits => (2, 1),
+            Op::Store | Op::StoreByte | Op::MemSwap | Op::MemSort | Op::CJmp | Op::RCJmp => (2, 0),
+            Op::JmpNeg | Op::JmpPos | Op::JmpZero => (2, 0),
+            Op::Swap => (2, 2),
+            Op::Tuck => (2, 3),
+            Op::Select | Op::Clamp | Op::MemCount | Op::MemEq | Op::Cas => (3, 1),
+            Op::MemCopy | Op::MemFill | Op::JmpEq | Op::JmpNe | Op::JmpLt | Op::JmpGt => (3, 0),
+            Op::Read | Op::DupAll | Op::DropN | Op::ReverseN | Op::Syscall(_) => return None,
+        };
+        Some(effect)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    StackUnderflow,      // an op needed more values than the stack holds
+    DivideByZero,        // the divisor was zero
+    Overflow,            // the result does not fit in an i64
+    BadMemoryAccess,     // a negative or out-of-range memory address
+    BadLabel,            // a jump to a label that was never defined
+    BadJump,             // a relative jump out of the program
+    BadArgument,         // a value outside of what the op is defined for
+    BadInput,            // the input was not an integer
+    Io,                  // reading input or writing output failed
+    OutputLimitExceeded, // the program tried to write more than the output limit
+    UnknownSyscall,      // no host function is registered under the id
+    DuplicateLabel,      // the program defines a label more than once
+}
+
+impl VmError {
+    /// Whether a handler registered with `Op::Trap` can catch this error.
+    /// Errors in the shape of the program itself, such as a stack underflow or
+    /// a jump to an undefined label, are never caught.
+    pub fn is_catchable(&self) -> bool {
+        matches!(
+            self,
+            VmError::DivideByZero
+                | VmError::Overflow
+                | VmError::BadMemoryAccess
+                | VmError::BadArgument
+        )
+    }
+
+    /// The code pushed for the trap handler when this error is caught.
+    pub fn code(&self) -> i64 {
+        match self {
+            VmError::StackUnderflow => 1,
+            VmError::DivideByZero => 2,
+            VmError::Overflow => 3,
+            VmError::BadMemoryAccess => 4,
+            VmError::BadLabel => 5,
+            VmError::BadJump => 6,
+            VmError::BadArgument => 7,
+            VmError::BadInput => 8,
+            VmError::Io => 9,
+            VmError::OutputLimitExceeded => 10,
+            VmError::UnknownSyscall => 11,
+            VmError::DuplicateLabel => 12,
+        }
+    }
+}
+
+impl core::fmt::Display for VmError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let msg = match self {
+            VmError::StackUnderflow => "stack underflow",
+            VmError::DivideByZero => "divide by zero",
+            VmError::Overflow => "arithmetic overflow",
+            VmError::BadMemoryAccess => "bad memory access",
+            VmError::BadLabel => "jump to undefined label",
+            VmError::BadJump => "jump out of the program",
+            VmError::BadArgument => "bad argument",
+            VmError::BadInput => "input is not an integer",
+            VmError::Io => "i/o error",
+            VmError::OutputLimitExceeded => "output limit exceeded",
+            VmError::UnknownSyscall => "unknown syscall",
+            VmError::DuplicateLabel => "label defined more than once",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl core::error::Error for VmError {}
+
+/// The final state of a VM, see `VM::finish`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmOutcome {
+    pub stack: Vec<i64>,
+    pub memory: Vec<i64>,
+    pub executed: u64,     // number of instructions executed
+    pub outputs: Vec<i64>, // values written by Put, if they were recorded
+}
+
+/// One input a program read from outside the VM, see `VM::record_inputs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    Int(Option<i64>), // read by Read, None at the end of input
+    Bytes(Vec<u8>),   // read by ReadAll
+    Failed(VmError),  // a read that failed
+}
+
+/// How often one kind of op ran and how long it took in total, see
+/// `VM::profile`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpProfile {
+    pub count: u64,
+    pub time: std::time::Duration,
+}
+
+/// The number of registers `Op::ToReg` and `Op::FromReg` can use.
+pub const REGISTERS: usize = 16;
+
+/// How many values from the top of the stack a `Fault` keeps.
+pub const FAULT_STACK: usize = 8;
+
+/// Where and in what state a program failed, see `VM::fault`.
+///
+/// This is kept on the VM rather than in `VmError`, which stays a plain
+/// `Copy` enum that callers compare and turn into codes; the context is only
+/// built once a run has failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fault {
+    pub error: VmError,
+    pub at: usize,       // index of the instruction that raised the error
+    pub stack: Vec<i64>, // the top of the stack when it failed, top last
+}
+
+impl core::fmt::Display for Fault {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "instruction {}: {} (stack {:?})",
+            self.at, self.error, self.stack
+        )
+    }
+}
+
+/// Everything needed to resume a VM exactly where it was, see `VM::snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmState {
+    pub stack: Vec<i64>,
+    pub memory: Vec<i64>,
+    pub jump_table: Vec<usize>,
+    pub ip: usize,
+    pub trap: Option<usize>,
+    pub registers: [i64; REGISTERS],
+}
+
+/// What a division by zero results in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DivByZero {
+    Error,    // fail with `VmError::DivideByZero`
+    Zero,     // push zero
+    Saturate, // push i64::MAX or i64::MIN following the sign of the dividend, or zero for 0 / 0
+}
+
+/// What arithmetic does when its result does not fit in an i64. The checks
+/// are explicit, so debug and release builds behave the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowMode {
+    Error,    // fail with `VmError::Overflow`
+    Wrap,     // wrap around in two's complement
+    Saturate, // clamp to i64::MIN or i64::MAX
+}
+
+// What to do after an op has executed
+enum Flow {
+    Next,        // continue with the following instruction
+    Goto(usize), // continue at the given instruction
+    Halt,        // stop the program
+}
+
+pub struct VM {
+    stack: Vec<i64>,
+    memory: Vec<i64>,
+    zero_filled: usize, // addresses below this read as zero until stored to
+    jump_table: Vec<usize>,
+    ip: usize,
+    trap: Option<usize>, // resolved address of the error handler
+    registers: [i64; REGISTERS],
+    div_by_zero: DivByZero,
+    overflow: OverflowMode,
+    data: Vec<i64>, // read-only constant pool
+    #[cfg(feature = "std")]
+    input: Option<Box<dyn BufRead>>, // stdin when unset
+    #[cfg(feature = "std")]
+    output: Option<Box<dyn Write>>, // stdout when unset
+    #[cfg(not(feature = "std"))]
+    output: Option<Box<dyn core::fmt::Write>>, // writing fails when unset
+    recorded: Option<Vec<i64>>, // values written by Put, when recording
+    logged: Option<Vec<Input>>, // inputs read, when recording them
+    replay: Option<VecDeque<Input>>, // inputs to read instead, when replaying
+    trace_hook: Option<Box<TraceHook>>,
+    underflow_hook: Option<Box<UnderflowHook>>,
+    syscalls: BTreeMap<usize, Box<Syscall>>,
+    mapped: Vec<MappedRange>,
+    args: Vec<String>, // read by Arg
+    executed: u64,     // number of instructions executed
+    output_limit: Option<usize>,
+    written: usize,         // bytes of output written
+    exit_code: Option<i64>, // code passed to Exit by the last run
+    put: Option<i64>,       // the last value written by Put, for run_until_output
+    paused: bool,           // whether run_until_output stopped before the end
+    fault: Option<Fault>,   // the error that ended the last run
+    #[cfg(feature = "std")]
+    profile: Option<HashMap<&'static str, OpProfile>>, // per op kind, when profiling
+}
+
+/// Called with the instruction pointer, the op and the stack before each
+/// instruction executes, see `VM::set_trace_hook`.
+pub type TraceHook = dyn FnMut(usize, &Op, &[i64]);
+
+/// Called with the failed VM when a program underflows the stack, see
+/// `VM::set_underflow_hook`.
+pub type UnderflowHook = dyn FnMut(&VM);
+
+/// A host function called by `Op::Syscall`, see `VM::register_syscall`.
+pub type Syscall = dyn FnMut(&mut Vec<i64>) -> Result<(), VmError>;
+
+/// Called with the address when `Op::Load` reads a mapped address, see
+/// `VM::map_memory`.
+pub type MmioRead = dyn FnMut(usize) -> Result<i64, VmError>;
+
+/// Called with the address and the value when `Op::Store` writes a mapped
+/// address, see `VM::map_memory`.
+pub type MmioWrite = dyn FnMut(usize, i64) -> Result<(), VmError>;
+
+// An address range whose loads and stores go to the host
+struct MappedRange {
+    range: core::ops::Range<usize>,
+    read: Box<MmioRead>,
+    write: Box<MmioWrite>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    pub const fn new() -> VM {
+        VM {
+            stack: Vec::new(),
+            memory: Vec::new(),
+            zero_filled: 0,
+            jump_table: Vec::new(),
+            ip: 0,
+            trap: None,
+            registers: [0; REGISTERS],
+            div_by_zero: DivByZero::Error,
+            overflow: OverflowMode::Error,
+            data: Vec::new(),
+            #[cfg(feature = "std")]
+            input: None,
+            output: None,
+            recorded: None,
+            logged: None,
+            replay: None,
+            trace_hook: None,
+            underflow_hook: None,
+            syscalls: BTreeMap::new(),
+            mapped: Vec::new(),
+            args: Vec::new(),
+            executed: 0,
+            output_limit: None,
+            written: 0,
+            exit_code: None,
+            put: None,
+            paused: false,
+            fault: None,
+            #[cfg(feature = "std")]
+            profile: None,
+        }
+    }
+
+    /// Calls `hook` before every instruction the VM executes.
+    pub fn set_trace_hook(&mut self, hook: impl FnMut(usize, &Op, &[i64]) + 'static) {
+        self.trace_hook = Some(Box::new(hook));
+    }
+
+    /// Calls `hook` when a program fails with `VmError::StackUnderflow`,
+    /// before the error is returned, so the state it failed in can be shown
+    /// right away, for example with `|vm| vm.dump()`. `VM::fault` already
+    /// tells where it failed when the hook runs. By default nothing is
+    /// called and the error is only returned.
+    pub fn set_underflow_hook(&mut self, hook: impl FnMut(&VM) + 'static) {
+        self.underflow_hook = Some(Box::new(hook));
+    }
+
+    /// Registers `f` as the host function `Op::Syscall(id)` calls, replacing
+    /// any function already registered under `id`.
+    ///
+    /// The function is given the whole stack. It pops its arguments off the
+    /// top, with the first argument on top, and pushes its results, and
+    /// whatever it leaves on the stack is what the program sees afterwards.
+    /// An error it returns is raised by the `Syscall` op like any other,
+    /// so a trap handler catches it when it is catchable.
+    pub fn register_syscall(
+        &mut self,
+        id: usize,
+        f: impl FnMut(&mut Vec<i64>) -> Result<(), VmError> + 'static,
+    ) {
+        self.syscalls.insert(id, Box::new(f));
+    }
+
+    /// Maps the addresses in `range` to the host: `Op::Load` from one of them
+    /// calls `read` with the address and pushes what it returns, and
+    /// `Op::Store` calls `write` with the address and the value.
+    ///
+    /// Mapped addresses take precedence over memory, which is neither read
+    /// nor written through them and does not need to extend that far. When
+    /// ranges overlap, the one mapped last wins. Only `Load` and `Store` are
+    /// mapped; the byte and block memory ops always use memory.
+    pub fn map_memory(
+        &mut self,
+        range: core::ops::Range<usize>,
+        read: impl FnMut(usize) -> Result<i64, VmError> + 'static,
+        write: impl FnMut(usize, i64) -> Result<(), VmError> + 'static,
+    ) {
+        self.mapped.push(MappedRange {
+            range,
+            read: Box::new(read),
+            write: Box::new(write),
+        });
+    }
+
+    // The mapped range an address falls in, if any
+    fn mapping(&mut self, ptr: usize) -> Option<&mut MappedRange> {
+        self.mapped
+            .iter_mut()
+            .rev()
+            .find(|mapped| mapped.range.contains(&ptr))
+    }
+
+    /// Sets the arguments `Op::Arg` reads, usually the command line arguments
+    /// after the program.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    /// Makes `Op::Read` read from `input` instead of stdin.
+    #[cfg(feature = "std")]
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        self.input = Some(Box::new(input));
+    }
+
+    /// Sends everything the program writes to `output` instead of stdout.
+    #[cfg(feature = "std")]
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Some(Box::new(output));
+    }
+
+    /// Sends everything the program writes to `output`. Without `std` there
+    /// is no stdout, so writing fails with `VmError::Io` until this is set.
+    #[cfg(not(feature = "std"))]
+    pub fn set_output(&mut self, output: impl core::fmt::Write + 'static) {
+        self.output = Some(Box::new(output));
+    }
+
+    /// Starts keeping every value written by `Op::Put`, in addition to
+    /// writing it to the output, until they are collected with
+    /// `take_outputs`.
+    pub fn record_outputs(&mut self) {
+        self.recorded.get_or_insert_with(Vec::new);
+    }
+
+    /// Starts keeping every input read by `Op::Read` and `Op::ReadAll`,
+    /// including failed reads, until they are collected with `take_inputs`.
+    /// Giving them to `replay_inputs` reproduces the run exactly.
+    pub fn record_inputs(&mut self) {
+        self.logged.get_or_insert_with(Vec::new);
+    }
+
+    /// Returns the inputs recorded since `record_inputs`, and clears them.
+    pub fn take_inputs(&mut self) -> Vec<Input> {
+        self.logged
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Makes `Op::Read` and `Op::ReadAll` take their results from `inputs`,
+    /// recorded with `record_inputs`, instead of reading the input. A read
+    /// fails with `VmError::Io` once the inputs run out or when the next one
+    /// was read by the other op.
+    pub fn replay_inputs(&mut self, inputs: Vec<Input>) {
+        self.replay = Some(inputs.into());
+    }
+
+    /// Starts timing every instruction, accumulated per kind of op, until
+    /// the profile is collected with `take_profile`.
+    #[cfg(feature = "std")]
+    pub fn profile(&mut self) {
+        self.profile.get_or_insert_with(HashMap::new);
+    }
+
+    /// Returns the profile gathered since `profile`, keyed by `Op::name`, and
+    /// clears it.
+    #[cfg(feature = "std")]
+    pub fn take_profile(&mut self) -> HashMap<&'static str, OpProfile> {
+        self.profile
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Returns the values recorded since `record_outputs`, and clears them.
+    pub fn take_outputs(&mut self) -> Vec<i64> {
+        self.recorded
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    // Reads the next whitespace separated integer from the input
+    #[cfg(feature = "std")]
+    fn read_int(&mut self) -> Result<Option<i64>, VmError> {
+        let mut stdin;
+        let input: &mut dyn BufRead = match &mut self.input {
+            Some(input) => input,
+            None => {
+                stdin = std::io::stdin().lock();
+                &mut stdin
+            }
+        };
+        let mut token = Vec::new();
+        for byte in input.bytes() {
+            let byte = byte.map_err(|_| VmError::Io)?;
+            if !byte.is_ascii_whitespace() {
+                token.push(byte);
+            } else if !token.is_empty() {
+                break;
+            }
+        }
+        if token.is_empty() {
+            return Ok(None);
+        }
+        std::str::from_utf8(&token)
+            .ok()
+            .and_then(|token| token.parse().ok())
+            .map(Some)
+            .ok_or(VmError::BadInput)
+    }
+
+    // Reads everything left in the input
+    #[cfg(feature = "std")]
+    fn read_to_end(&mut self) -> Result<Vec<u8>, VmError> {
+        let mut bytes = Vec::new();
+        let res = match &mut self.input {
+            Some(input) => input.read_to_end(&mut bytes),
+            None => std::io::stdin().lock().read_to_end(&mut bytes),
+        };
+        res.map_err(|_| VmError::Io)?;
+        Ok(bytes)
+    }
+
+    // Reads an integer for Read, from the replayed inputs when replaying
+    fn input_int(&mut self) -> Result<Option<i64>, VmError> {
+        let res = match &mut self.replay {
+            Some(replay) => match replay.pop_front() {
+                Some(Input::Int(a)) => Ok(a),
+                Some(Input::Failed(error)) => Err(error),
+                _ => Err(VmError::Io),
+            },
+            None => self.read_int(),
+        };
+        if let Some(logged) = &mut self.logged {
+            logged.push(match res {
+                Ok(a) => Input::Int(a),
+                Err(error) => Input::Failed(error),
+            });
+        }
+        res
+    }
+
+    // Reads the rest of the input for ReadAll, like `input_int`
+    fn input_bytes(&mut self) -> Result<Vec<u8>, VmError> {
+        let res = match &mut self.replay {
+            Some(replay) => match replay.pop_front() {
+                Some(Input::Bytes(bytes)) => Ok(bytes),
+                Some(Input::Failed(error)) => Err(error),
+                _ => Err(VmError::Io),
+            },
+            None => self.read_to_end(),
+        };
+        if let Some(logged) = &mut self.logged {
+            logged.push(match &res {
+                Ok(bytes) => Input::Bytes(bytes.clone()),
+                Err(error) => Input::Failed(*error),
+            });
+        }
+        res
+    }
+
+    // There is no input without std
+    #[cfg(not(feature = "std"))]
+    fn read_int(&mut self) -> Result<Option<i64>, VmError> {
+        Err(VmError::Io)
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn read_to_end(&mut self) -> Result<Vec<u8>, VmError> {
+        Err(VmError::Io)
+    }
+
+    fn write(&mut self, text: &str) -> Result<(), VmError> {
+        let written = self.written + text.len();
+        if self.output_limit.is_some_and(|limit| written > limit) {
+            return Err(VmError::OutputLimitExceeded);
+        }
+        self.written = written;
+        #[cfg(feature = "std")]
+        let res = match &mut self.output {
+            Some(output) => output.write_all(text.as_bytes()).is_ok(),
+            None => std::io::stdout().write_all(text.as_bytes()).is_ok(),
+        };
+        #[cfg(not(feature = "std"))]
+        let res = match &mut self.output {
+            Some(output) => output.write_str(text).is_ok(),
+            None => false,
+        };
+        res.then_some(()).ok_or(VmError::Io)
+    }
+
+    /// Limits the number of bytes the program may write in total. Output
+    /// that would go past the limit fails with
+    /// `VmError::OutputLimitExceeded` instead of being written.
+    pub fn set_output_limit(&mut self, max_bytes: usize) {
+        self.output_limit = Some(max_bytes);
+    }
+
+    /// Sets the read-only constant pool that `Op::Const` reads from, usually
+    /// the `data` of an `Assembly`.
+    pub fn set_data(&mut self, data: Vec<i64>) {
+        self.data = data;
+    }
+
+    /// Sets what arithmetic that overflows an i64 does, see `OverflowMode`.
+    pub fn set_overflow_mode(&mut self, mode: OverflowMode) {
+        self.overflow = mode;
+    }
+
+    /// Sets what dividing by zero does, see `DivByZero`.
+    pub fn set_div_by_zero(&mut self, policy: DivByZero) {
+        self.div_by_zero = policy;
+    }
+
+    /// Creates a VM whose memory is preallocated with `size` zeroed cells, so
+    /// any address below `size` can be loaded without storing to it first.
+    pub fn with_memory_size(size: usize) -> VM {
+        VM {
+            memory: vec![0; size],
+            ..VM::new()
+        }
+    }
+
+    /// Makes memory behave as if it held `size` zeroed cells without
+    /// allocating them: `Op::Load` from an address below `size` that was
+    /// never stored to pushes zero, and `Op::Store` to any address below
+    /// `size` grows memory up to it. Addresses from `size` on work as usual,
+    /// so memory can still grow one cell at a time past it. Memory only
+    /// takes up as much space as the highest address stored to, unlike with
+    /// `with_memory_size`. The byte and block memory ops only see memory as
+    /// far as it has grown.
+    pub fn set_zero_filled(&mut self, size: usize) {
+        self.zero_filled = size;
+    }
+
+    fn pop(&mut self) -> Result<i64, VmError> {
+        self.stack.pop().ok_or(VmError::StackUnderflow)
+    }
+
+    fn address(ptr: i64) -> Result<usize, VmError> {
+        usize::try_from(ptr).map_err(|_| VmError::BadMemoryAccess)
+    }
+
+    fn region(&self, base: i64, len: i64) -> Result<core::ops::Range<usize>, VmError> {
+        let base = Self::address(base)?;
+        let len = Self::address(len)?;
+        let end = base.checked_add(len).ok_or(VmError::BadMemoryAccess)?;
+        if end > self.memory.len() {
+            return Err(VmError::BadMemoryAccess);
+        }
+        Ok(base..end)
+    }
+
+    // Applies the overflow mode to the result of an arithmetic op, given as
+    // the wrapped result with whether it overflowed, and the saturated result
+    fn arith(&self, (wrapped, overflowed): (i64, bool), saturated: i64) -> Result<i64, VmError> {
+        if !overflowed {
+            return Ok(wrapped);
+        }
+        match self.overflow {
+            OverflowMode::Error => Err(VmError::Overflow),
+            OverflowMode::Wrap => Ok(wrapped),
+            OverflowMode::Saturate => Ok(saturated),
+        }
+    }
+
+    // Applies a division-like op, or the divide by zero policy when b is zero
+    fn divide(&self, a: i64, b: i64, op: fn(i64, i64) -> i64) -> Result<i64, VmError> {
+        if a == i64::MIN && b == -1 {
+            return self.arith((i64::MIN, true), i64::MAX);
+        }
+        if b != 0 {
+            return Ok(op(a, b));
+        }
+        match self.div_by_zero {
+            DivByZero::Error => Err(VmError::DivideByZero),
+            DivByZero::Zero => Ok(0),
+            DivByZero::Saturate => Ok(match a.signum() {
+                1 => i64::MAX,
+                -1 => i64::MIN,
+                _ => 0,
+            }),
+        }
+    }
+
+    // Relative jumps may land on any instruction, or just past the last one to
+    // end the program
+    fn relative_target(&self, program: &[Op], offset: i64) -> Result<usize, VmError> {
+        (self.ip as i64)
+            .checked_add(offset)
+            .and_then(|target| usize::try_from(target).ok())
+            .filter(|target| *target <= program.len())
+            .ok_or(VmError::BadJump)
+    }
+
+    fn jump_target(&self, label: i64) -> Result<usize, VmError> {
+        let label = usize::try_from(label).map_err(|_| VmError::BadLabel)?;
+        self.jump_table.get(label).copied().ok_or(VmError::BadLabel)
+    }
+
+    /// Formats a runtime error together with the source location of the
+    /// instruction that raised it. `spans` is the table produced by
+    /// `Lexer::codegen_with_spans` for the program that was executed.
+    pub fn format_error(&self, err: &VmError, spans: &[Span]) -> String {
+        match spans.get(self.ip) {
+            Some(span) => format!("{}:{}: {}", span.line, span.col, err),
+            None => format!("instruction {}: {}", self.ip, err),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn dump(&self) {
+        print!("{}", self.dump_string());
+    }
+
+    /// The state printed by `dump`.
+    pub fn dump_string(&self) -> String {
+        format!(
+            "stack {:?}\nmemory {:?}\njmp table {:?}\n",
+            self.stack, self.memory, self.jump_table
+        )
+    }
+
+    /// Writes a hexdump of `len` memory cells starting at `start`, four cells
+    /// per row prefixed by the address of the first cell. Cells past the end
+    /// of memory are not printed.
+    #[cfg(feature = "std")]
+    pub fn dump_memory_region(
+        &self,
+        start: usize,
+        len: usize,
+        w: &mut impl std::io::Write,
+    ) -> std::io::Result<()> {
+        let end = start.saturating_add(len).min(self.memory.len());
+        let region = self.memory.get(start..end).unwrap_or_default();
+        for (row, cells) in region.chunks(4).enumerate() {
+            write!(w, "{:08x}:", start + row * 4)?;
+            for cell in cells {
+                write!(w, " {:016x}", cell)?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    pub fn execute(&mut self, program: &[Op]) -> Result<Option<i64>, VmError> {
+        self.prepare(program)?;
+        while self.step(program)? {
+            // std::thread::sleep(std::time::Duration::from_nanos(100));
+        }
+        match self.exit_code {
+            Some(code) => Ok(Some(code)),
+            None => Ok(self.stack.pop()),
+        }
+    }
+
+    /// Runs `programs` one after another on the same memory, and returns
+    /// what `execute` returns for the last one.
+    ///
+    /// Before each program the stack is cleared and the trap handler is
+    /// removed, and the jump table and entry point are set up for that
+    /// program alone, so labels and traps never carry over. Memory, the
+    /// registers and all settings persist. The sequence stops at the first
+    /// program that fails or ends with `Op::Exit`.
+    pub fn run_sequence(&mut self, programs: &[&[Op]]) -> Result<Option<i64>, VmError> {
+        let mut res = None;
+        for program in programs {
+            self.stack.clear();
+            self.trap = None;
+            res = self.execute(program)?;
+            if self.exit_code.is_some() {
+                break;
+            }
+        }
+        Ok(res)
+    }
+
+    /// Runs `program` until it writes a value with `Op::Put` or
+    /// `Op::PutCr`, and returns that value, or None when the program ends
+    /// first. The value is still written to the output.
+    ///
+    /// The VM stays paused after the instruction that wrote the value, and
+    /// the next call with the same program continues from there, so a
+    /// generator program can be pulled from one value at a time. A call after
+    /// the program ended or failed starts it over like `execute`.
+    pub fn run_until_output(&mut self, program: &[Op]) -> Result<Option<i64>, VmError> {
+        if !self.paused {
+            self.prepare(program)?;
+        }
+        self.paused = false;
+        self.put = None;
+        loop {
+            let more = self.step(program)?;
+            if let Some(a) = self.put.take() {
+                self.paused = more;
+                return Ok(Some(a));
+            }
+            if !more {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// The error that ended the last run, if it failed, with the index of the
+    /// failing instruction and up to `FAULT_STACK` values from the top of
+    /// the stack as the failing op left it, without any operands it had
+    /// already popped. Errors caught by a trap handler are not recorded.
+    pub fn fault(&self) -> Option<&Fault> {
+        self.fault.as_ref()
+    }
+
+    /// The code the last program passed to `Op::Exit`, if it exited that way.
+    pub fn exit_code(&self) -> Option<i64> {
+        self.exit_code
+    }
+
+    #[deprecated(note = "renamed to `execute`")]
+    pub fn excecute(&mut self, program: &[Op]) -> Result<Option<i64>, VmError> {
+        self.execute(program)
+    }
+
+    /// Builds the jump table for `program` and moves the instruction pointer
+    /// to its entry point. `execute` does this itself; call it directly only
+    /// before driving a program with `step`.
+    ///
+    /// Each label has to be defined by a unique `Op::Label`, and a program
+    /// that defines one twice fails with `VmError::DuplicateLabel`. The label
+    /// resolves to the index of that op, whatever order the labels appear
+    /// in. A jump to it sets the instruction pointer to the label marker,
+    /// which does nothing, and execution continues with the instruction
+    /// right after it.
+    pub fn prepare(&mut self, program: &[Op]) -> Result<(), VmError> {
+        self.exit_code = None;
+        self.fault = None;
+        self.paused = false;
+
+        // Populate jump table
+        self.jump_table.clear();
+        if let Err((error, at)) = check_labels(program) {
+            return Err(self.reject(error, at));
+        }
+        self.jump_table.extend(labels(program).into_values());
+
+        // Start at the first entry point marker, or at the top when there is none
+        self.ip = program.iter().position(|op| *op == Op::Start).unwrap_or(0);
+        Ok(())
+    }
+
+    // Records the fault for a program `prepare` rejects because of the label
+    // at `at`
+    fn reject(&mut self, error: VmError, at: usize) -> VmError {
+        self.ip = at;
+        self.record_fault(error, at);
+        error
+    }
+
+    // Keeps the context of an error that ends the run, only called once it
+    // has failed so that `step` does no extra work for the ops that succeed
+    fn record_fault(&mut self, error: VmError, at: usize) {
+        self.fault = Some(Fault {
+            error,
+            at,
+            stack: self.stack[self.stack.len().saturating_sub(FAULT_STACK)..].to_vec(),
+        });
+    }
+
+    /// The instruction each label resolves to, indexed by label id, as set up
+    /// by `prepare` for the last program.
+    pub fn jump_table(&self) -> &[usize] {
+        &self.jump_table
+    }
+
+    /// Makes jumps to `label` go to instruction `target` instead, for
+    /// debuggers and loaders that patch a prepared program. Execution
+    /// continues with the instruction after `target`, like after a label
+    /// marker, and a target at or past the last instruction ends the
+    /// program. Only labels the program defines can be redirected, others
+    /// fail with `VmError::BadLabel`. The next `prepare` or `execute`
+    /// rebuilds the table and drops the change.
+    pub fn set_jump_target(&mut self, label: usize, target: usize) -> Result<(), VmError> {
+        let entry = self.jump_table.get_mut(label).ok_or(VmError::BadLabel)?;
+        *entry = target;
+        Ok(())
+    }
+
+    /// Executes a single instruction of a program set up with `prepare`.
+    /// Returns whether there is anything left to run.
+    pub fn step(&mut self, program: &[Op]) -> Result<bool, VmError> {
+        if self.ip >= program.len() {
+            return Ok(false);
+        }
+        if let Some(hook) = &mut self.trace_hook {
+            hook(self.ip, &program[self.ip], &self.stack);
+        }
+        self.executed += 1;
+        #[cfg(feature = "std")]
+        let ip = self.ip;
+        #[cfg(feature = "std")]
+        let start = self.profile.is_some().then(std::time::Instant::now);
+        let res = self.exec_op(program);
+        #[cfg(feature = "std")]
+        if let (Some(profile), Some(start)) = (&mut self.profile, start) {
+            let entry = profile.entry(program[ip].name()).or_default();
+            entry.count += 1;
+            entry.time += start.elapsed();
+        }
+        match res {
+            Ok(Flow::Next) => (),
+            Ok(Flow::Goto(target)) => {
+                self.ip = target;
+                return Ok(self.ip < program.len());
+            }
+            Ok(Flow::Halt) => {
+                self.ip = program.len();
+                return Ok(false);
+            }
+            Err(err) => match self.trap {
+                Some(handler) if err.is_catchable() => {
+                    self.stack.push(err.code());
+                    self.ip = handler;
+                }
+                _ => {
+                    self.record_fault(err, self.ip);
+                    if err == VmError::StackUnderflow {
+                        if let Some(mut hook) = self.underflow_hook.take() {
+                            hook(self);
+                            self.underflow_hook = Some(hook);
+                        }
+                    }
+                    return Err(err);
+                }
+            },
+        }
+        self.ip += 1;
+        Ok(self.ip < program.len())
+    }
+
+    /// Runs `program` like `execute`, but instead of the top of the stack
+    /// returns the indices of the instructions that never executed. Labels
+    /// and the entry point marker do nothing and are never reported.
+    pub fn execute_with_coverage(&mut self, program: &[Op]) -> Result<Vec<usize>, VmError> {
+        let mut visited = vec![false; program.len()];
+        self.prepare(program)?;
+        loop {
+            if let Some(visited) = visited.get_mut(self.ip) {
+                *visited = true;
+            }
+            if !self.step(program)? {
+                break;
+            }
+        }
+        Ok(program
+            .iter()
+            .zip(visited)
+            .enumerate()
+            .filter(|(_, (op, visited))| !visited && !matches!(op, Op::Label(_) | Op::Start))
+            .map(|(i, _)| i)
+            .collect())
+    }
+
+    #[deprecated(note = "renamed to `execute_with_coverage`")]
+    pub fn excecute_with_coverage(&mut self, program: &[Op]) -> Result<Vec<usize>, VmError> {
+        self.execute_with_coverage(program)
+    }
+
+    /// Consumes the VM and returns its final state.
+    pub fn finish(mut self) -> VmOutcome {
+        VmOutcome {
+            outputs: self.take_outputs(),
+            stack: self.stack,
+            memory: self.memory,
+            executed: self.executed,
+        }
+    }
+
+    /// Captures the complete state of the VM.
+    pub fn snapshot(&self) -> VmState {
+        VmState {
+            stack: self.stack.clone(),
+            memory: self.memory.clone(),
+            jump_table: self.jump_table.clone(),
+            ip: self.ip,
+            trap: self.trap,
+            registers: self.registers,
+        }
+    }
+
+    /// Puts the VM back into a state captured by `snapshot`.
+    pub fn restore(&mut self, state: VmState) {
+        self.stack = state.stack;
+        self.memory = state.memory;
+        self.jump_table = state.jump_table;
+        self.ip = state.ip;
+        self.trap = state.trap;
+        self.registers = state.registers;
+    }
+
+    /// Like `execute`, but also reports the wall time the run took.
+    #[cfg(feature = "std")]
+    pub fn execute_timed(
+        &mut self,
+        program: &[Op],
+    ) -> (Result<Option<i64>, VmError>, std::time::Duration) {
+        let start = std::time::Instant::now();
+        let res = self.execute(program);
+        (res, start.elapsed())
+    }
+
+    #[cfg(feature = "std")]
+    #[deprecated(note = "renamed to `execute_timed`")]
+    pub fn excecute_timed(
+        &mut self,
+        program: &[Op],
+    ) -> (Result<Option<i64>, VmError>, std::time::Duration) {
+        self.execute_timed(program)
+    }
+
+    // Executes the op at the instruction pointer
+    fn exec_op(&mut self, program: &[Op]) -> Result<Flow, VmError> {
+        let op = &program[self.ip];
+        match op {
+            Op::Add => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                let x = self.arith(a.overflowing_add(b), a.saturating_add(b))?;
+                self.stack.push(x);
+            }
+            Op::Sub => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                let x = self.arith(a.overflowing_sub(b), a.saturating_sub(b))?;
+                self.stack.push(x);
+            }
+            Op::Mul => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                let x = self.arith(a.overflowing_mul(b), a.saturating_mul(b))?;
+                self.stack.push(x);
+            }
+            Op::Div => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                let x = self.divide(a, b, |a, b| a / b)?;
+                self.stack.push(x);
+            }
+            Op::FloorDiv => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                let x = self.divide(a, b, |a, b| {
+                    let q = a / b;
+                    if a % b != 0 && (a < 0) != (b < 0) {
+                        q - 1
+                    } else {
+                        q
+                    }
+                })?;
+                self.stack.push(x);
+            }
+            Op::Sqrt => {
+                let a = self.pop()?;
+                if a < 0 {
+                    return Err(VmError::BadArgument);
+                }
+                self.stack.push(a.isqrt());
+            }
+            // 66! and up are multiples of 2^64, so wrapping stops changing
+            // the product there
+            Op::Fact => {
+                let n = self.pop()?;
+                if n < 0 {
+                    return Err(VmError::BadArgument);
+                }
+                let (mut x, mut overflowed) = (1i64, false);
+                for i in 2..=n.min(66) {
+                    let (product, o) = x.overflowing_mul(i);
+                    x = product;
+                    overflowed |= o;
+                }
+                let x = self.arith((x, overflowed), i64::MAX)?;
+                self.stack.push(x);
+            }
+            Op::Gcd => {
+                // Euclid's algorithm on the magnitudes, so the sign of the
+                // operands does not matter and gcd(0, n) = |n|
+                let mut a = self.pop()?.unsigned_abs();
+                let mut b = self.pop()?.unsigned_abs();
+                while b != 0 {
+                    (a, b) = (b, a % b);
+                }
+                let x = self.arith((a as i64, a > i64::MAX as u64), i64::MAX)?;
+                self.stack.push(x);
+            }
+            Op::AbsDiff => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                let diff = a.abs_diff(b);
+                let x = self.arith((diff as i64, diff > i64::MAX as u64), i64::MAX)?;
+                self.stack.push(x);
+            }
+            // Always an error for k = 0, whatever the divide by zero policy
+            Op::Divides => {
+                let k = self.pop()?;
+                let n = self.pop()?;
+                if k == 0 {
+                    return Err(VmError::DivideByZero);
+                }
+                self.stack.push((n.wrapping_rem(k) == 0) as i64);
+            }
+            Op::PopCount => {
+                let a = self.pop()?;
+                self.stack.push(a.count_ones() as i64);
+            }
+            Op::Parity => {
+                let a = self.pop()?;
+                self.stack.push((a.count_ones() & 1) as i64);
+            }
+            Op::Clz => {
+                let a = self.pop()?;
+                self.stack.push(a.leading_zeros() as i64);
+            }
+            Op::Ctz => {
+                let a = self.pop()?;
+                self.stack.push(a.trailing_zeros() as i64);
+            }
+            // Zero and negative values never are, not even minint, whose bits
+            // read as 2^63 when unsigned
+            Op::IsPow2 => {
+                let a = self.pop()?;
+                self.stack
+                    .push((a > 0 && (a as u64).is_power_of_two()) as i64);
+            }
+            // -1 rather than an error, so parsing loops can stop on it
+            Op::DigitVal => {
+                let a = self.pop()?;
+                let digit = u8::try_from(a)
+                    .ok()
+                    .and_then(|c| (c as char).to_digit(10))
+                    .map_or(-1, |digit| digit as i64);
+                self.stack.push(digit);
+            }
+            Op::RotL => {
+                let n = self.pop()?;
+                let a = self.pop()?;
+                self.stack.push(a.rotate_left((n & 63) as u32));
+            }
+            Op::RotR => {
+                let n = self.pop()?;
+                let a = self.pop()?;
+                self.stack.push(a.rotate_right((n & 63) as u32));
+            }
+            Op::Inc => {
+                let a = self.pop()?;
+                let x = self.arith(a.overflowing_add(1), a.saturating_add(1))?;
+                self.stack.push(x);
+            }
+            Op::Dec | Op::DecDup => {
+                let a = self.pop()?;
+                let x = self.arith(a.overflowing_sub(1), a.saturating_sub(1))?;
+                self.stack.push(x);
+                if *op == Op::DecDup {
+                    self.stack.push(x);
+                }
+            }
+            // Saturate whatever the overflow mode is
+            Op::SatAdd | Op::SatSub | Op::SatMul => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.stack.push(match op {
+                    Op::SatAdd => a.saturating_add(b),
+                    Op::SatSub => a.saturating_sub(b),
+                    _ => a.saturating_mul(b),
+                });
+            }
+            Op::Lit(x) => self.stack.push(*x),
+            Op::MaxInt => self.stack.push(i64::MAX),
+            Op::MinInt => self.stack.push(i64::MIN),
+            Op::Const(index) => {
+                let a = *self.data.get(*index).ok_or(VmError::BadMemoryAccess)?;
+                self.stack.push(a);
+            }
+            Op::Load => {
+                let ptr = Self::address(self.pop()?)?;
+                let a = match self.mapping(ptr) {
+                    Some(mapped) => (mapped.read)(ptr)?,
+                    None => match self.memory.get(ptr) {
+                        Some(a) => *a,
+                        None if ptr < self.zero_filled => 0,
+                        None => return Err(VmError::BadMemoryAccess),
+                    },
+                };
+                self.stack.push(a);
+            }
+            Op::Store => {
+                let ptr = Self::address(self.pop()?)?;
+                let a = self.pop()?;
+                if let Some(mapped) = self.mapping(ptr) {
+                    (mapped.write)(ptr, a)?;
+                    return Ok(Flow::Next);
+                }
+                if ptr > self.memory.len() {
+                    if ptr >= self.zero_filled {
+                        return Err(VmError::BadMemoryAccess);
+                    }
+                    self.memory.resize(ptr, 0);
+                }
+                if self.memory.len() <= ptr {
+                    self.memory.push(a);
+                } else {
+                    self.memory[ptr] = a;
+                }
+            }
+            // Byte address n is byte n % 8 of cell n / 8, counting from the
+            // least significant byte, so memory reads as little endian bytes
+            Op::LoadByte => {
+                let ptr = Self::address(self.pop()?)?;
+                let cell = *self.memory.get(ptr / 8).ok_or(VmError::BadMemoryAccess)?;
+                self.stack.push((cell >> (ptr % 8 * 8)) & 0xff);
+            }
+            Op::StoreByte => {
+                let ptr = Self::address(self.pop()?)?;
+                let a = self.pop()?;
+                if ptr / 8 > self.memory.len() {
+                    return Err(VmError::BadMemoryAccess);
+                }
+                if self.memory.len() == ptr / 8 {
+                    self.memory.push(0);
+                }
+                let shift = ptr % 8 * 8;
+                let cell = &mut self.memory[ptr / 8];
+                *cell = (*cell & !(0xff << shift)) | ((a & 0xff) << shift);
+            }
+            Op::MemCopy => {
+                let len = self.pop()?;
+                let src = self.pop()?;
+                let dst = self.pop()?;
+                let src = self.region(src, len)?;
+                let dst = self.region(dst, len)?;
+                self.memory.copy_within(src, dst.start);
+            }
+            Op::MemFill => {
+                let a = self.pop()?;
+                let len = Self::address(self.pop()?)?;
+                let base = Self::address(self.pop()?)?;
+                // Like store, the fill may start at the end of memory and grow it
+                if base > self.memory.len() {
+                    return Err(VmError::BadMemoryAccess);
+                }
+                let end = base.checked_add(len).ok_or(VmError::BadMemoryAccess)?;
+                if end > self.memory.len() {
+                    self.memory.resize(end, 0);
+                }
+                self.memory[base..end].fill(a);
+            }
+            Op::MemSwap => {
+                let a = Self::address(self.pop()?)?;
+                let b = Self::address(self.pop()?)?;
+                if a >= self.memory.len() || b >= self.memory.len() {
+                    return Err(VmError::BadMemoryAccess);
+                }
+                self.memory.swap(a, b);
+            }
+            Op::MemCount => {
+                let a = self.pop()?;
+                let len = self.pop()?;
+                let base = self.pop()?;
+                let cells = &self.memory[self.region(base, len)?];
+                let count = cells.iter().filter(|cell| **cell == a).count();
+                self.stack.push(count as i64);
+            }
+            Op::MemSort => {
+                let len = self.pop()?;
+                let base = self.pop()?;
+                let region = self.region(base, len)?;
+                self.memory[region].sort();
+            }
+            // 64-bit FNV-1a over the little endian bytes of every cell, so
+            // the hash is the same on every platform
+            Op::MemHash => {
+                let len = self.pop()?;
+                let base = self.pop()?;
+                let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+                for cell in &self.memory[self.region(base, len)?] {
+                    for byte in cell.to_le_bytes() {
+                        hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+                    }
+                }
+                self.stack.push(hash as i64);
+            }
+            Op::MemEq => {
+                let len = self.pop()?;
+                let a = self.pop()?;
+                let b = self.pop()?;
+                let (a, b) = (self.region(a, len)?, self.region(b, len)?);
+                let equal = self.memory[a] == self.memory[b];
+                self.stack.push(equal as i64);
+            }
+            Op::MemMax | Op::MemMin => {
+                let len = self.pop()?;
+                let base = self.pop()?;
+                let cells = self.memory[self.region(base, len)?].iter().copied();
+                let a = match op {
+                    Op::MemMax => cells.max(),
+                    _ => cells.min(),
+                };
+                self.stack.push(a.ok_or(VmError::BadArgument)?);
+            }
+            // Writes the digits of the magnitude, one per cell, most significant
+            // first, and pushes the count negated for negative values, so zero
+            // is the single digit 0 and -42 writes 4, 2 and pushes -2
+            Op::ToDigits => {
+                let a = self.pop()?;
+                let base = Self::address(self.pop()?)?;
+                // Like store, the digits may start at the end of memory and grow it
+                if base > self.memory.len() {
+                    return Err(VmError::BadMemoryAccess);
+                }
+                let digits = a.unsigned_abs().to_string();
+                let end = base
+                    .checked_add(digits.len())
+                    .ok_or(VmError::BadMemoryAccess)?;
+                if end > self.memory.len() {
+                    self.memory.resize(end, 0);
+                }
+                for (cell, digit) in self.memory[base..end].iter_mut().zip(digits.bytes()) {
+                    *cell = (digit - b'0') as i64;
+                }
+                let count = digits.len() as i64;
+                self.stack.push(if a < 0 { -count } else { count });
+            }
+            Op::Cas => {
+                let new = self.pop()?;
+                let expected = self.pop()?;
+                let ptr = Self::address(self.pop()?)?;
+                let cell = self.memory.get_mut(ptr).ok_or(VmError::BadMemoryAccess)?;
+                let swapped = *cell == expected;
+                if swapped {
+                    *cell = new;
+                }
+                self.stack.push(swapped as i64);
+            }
+            Op::Label(_) | Op::Start => (),
+            Op::Jmp => {
+                let label = self.pop()?;
+                self.ip = self.jump_target(label)?;
+            }
+            Op::CJmp | Op::JmpNeg | Op::JmpPos | Op::JmpZero => {
+                let label = self.pop()?;
+                let a = self.pop()?;
+                let taken = match op {
+                    Op::CJmp => a != 0,
+                    Op::JmpNeg => a < 0,
+                    Op::JmpPos => a > 0,
+                    _ => a == 0,
+                };
+                if taken {
+                    self.ip = self.jump_target(label)?;
+                }
+            }
+            Op::JmpEq | Op::JmpNe | Op::JmpLt | Op::JmpGt => {
+                let label = self.pop()?;
+                let a = self.pop()?;
+                let b = self.pop()?;
+                let taken = match op {
+                    Op::JmpEq => a == b,
+                    Op::JmpNe => a != b,
+                    Op::JmpLt => a < b,
+                    _ => a > b,
+                };
+                if taken {
+                    self.ip = self.jump_target(label)?;
+                }
+            }
+            Op::Put | Op::PutCr => {
+                let a = self.pop()?;
+                self.write(&a.to_string())?;
+                if let Some(recorded) = &mut self.recorded {
+                    recorded.push(a);
+                }
+                self.put = Some(a);
+                if *op == Op::PutCr {
+                    self.write("\n")?;
+                }
+            }
+            Op::CR => self.write("\n")?,
+            Op::PutStrMem => {
+                let mut ptr = Self::address(self.pop()?)?;
+                let mut text = String::new();
+                loop {
+                    let a = match self.memory.get(ptr) {
+                        Some(a) => *a,
+                        None if ptr < self.zero_filled => 0,
+                        None => return Err(VmError::BadMemoryAccess),
+                    };
+                    if a == 0 {
+                        break;
+                    }
+                    let c = u32::try_from(a).ok().and_then(char::from_u32);
+                    text.push(c.ok_or(VmError::BadArgument)?);
+                    ptr += 1;
+                }
+                self.write(&text)?;
+            }
+            Op::Read => match self.input_int()? {
+                Some(a) => {
+                    self.stack.push(a);
+                    self.stack.push(1);
+                }
+                None => self.stack.push(0),
+            },
+            Op::Arg => {
+                let index = usize::try_from(self.pop()?).map_err(|_| VmError::BadArgument)?;
+                let arg = self.args.get(index).ok_or(VmError::BadArgument)?;
+                let a = arg.parse().map_err(|_| VmError::BadInput)?;
+                self.stack.push(a);
+            }
+            Op::ReadAll => {
+                let base = Self::address(self.pop()?)?;
+                // Like memfill, the block may start at the end of memory and grow it
+                if base > self.memory.len() {
+                    return Err(VmError::BadMemoryAccess);
+                }
+                let bytes = self.input_bytes()?;
+                let end = base
+                    .checked_add(bytes.len())
+                    .ok_or(VmError::BadMemoryAccess)?;
+                if end > self.memory.len() {
+                    self.memory.resize(end, 0);
+                }
+                for (cell, byte) in self.memory[base..end].iter_mut().zip(&bytes) {
+                    *cell = *byte as i64;
+                }
+                self.stack.push(bytes.len() as i64);
+            }
+            Op::Dup => {
+                let a = self.pop()?;
+                self.stack.push(a);
+                self.stack.push(a);
+            }
+            Op::Swap => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.stack.push(a);
+                self.stack.push(b);
+            }
+            Op::Tuck => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.stack.push(a);
+                self.stack.push(b);
+                self.stack.push(a);
+            }
+            Op::Nip => {
+                let a = self.pop()?;
+                self.pop()?;
+                self.stack.push(a);
+            }
+            Op::DupAll => self.stack.extend_from_within(..),
+            Op::DropN => {
+                let n = usize::try_from(self.pop()?).map_err(|_| VmError::BadArgument)?;
+                let len = self
+                    .stack
+                    .len()
+                    .checked_sub(n)
+                    .ok_or(VmError::StackUnderflow)?;
+                self.stack.truncate(len);
+            }
+            Op::ReverseN => {
+                let n = usize::try_from(self.pop()?).map_err(|_| VmError::BadArgument)?;
+                let start = self
+                    .stack
+                    .len()
+                    .checked_sub(n)
+                    .ok_or(VmError::StackUnderflow)?;
+                self.stack[start..].reverse();
+            }
+            Op::ToReg(reg) => {
+                let a = self.pop()?;
+                *self
+                    .registers
+                    .get_mut(*reg as usize)
+                    .ok_or(VmError::BadArgument)? = a;
+            }
+            Op::FromReg(reg) => {
+                let a = *self
+                    .registers
+                    .get(*reg as usize)
+                    .ok_or(VmError::BadArgument)?;
+                self.stack.push(a);
+            }
+            Op::Eq => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.stack.push(if a == b { 1 } else { 0 });
+            }
+            Op::Lt => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.stack.push(if a < b { 1 } else { 0 });
+            }
+            Op::Gt => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.stack.push(if a > b { 1 } else { 0 });
+            }
+            Op::Select => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let cond = self.pop()?;
+                self.stack.push(if cond != 0 { a } else { b });
+            }
+            // There is no range to clamp to when min > max
+            Op::Clamp => {
+                let max = self.pop()?;
+                let min = self.pop()?;
+                let a = self.pop()?;
+                if min > max {
+                    return Err(VmError::BadArgument);
+                }
+                self.stack.push(a.clamp(min, max));
+            }
+            // Without std the dump goes to the output like everything else
+            Op::Dump => {
+                #[cfg(feature = "std")]
+                self.dump();
+                #[cfg(not(feature = "std"))]
+                self.write(&self.dump_string())?;
+            }
+            Op::Halt => {
+                return Ok(Flow::Halt);
+            }
+            Op::Exit => {
+                self.exit_code = Some(self.pop()?);
+                return Ok(Flow::Halt);
+            }
+            Op::ProgLen => self.stack.push(program.len() as i64),
+            Op::Here => self.stack.push(self.ip as i64),
+            Op::Trap => {
+                let label = self.pop()?;
+                self.trap = Some(self.jump_target(label)?);
+            }
+            Op::Syscall(id) => {
+                let f = self.syscalls.get_mut(id).ok_or(VmError::UnknownSyscall)?;
+                f(&mut self.stack)?;
+            }
+            Op::Switch(targets) => {
+                let index = self.pop()?;
+                if let Some(label) = usize::try_from(index).ok().and_then(|i| targets.get(i)) {
+                    self.ip = self.jump_target(*label as i64)?;
+                }
+            }
+            Op::RJmp => {
+                let offset = self.pop()?;
+                return Ok(Flow::Goto(self.relative_target(program, offset)?));
+            }
+            Op::RCJmp => {
+                let offset = self.pop()?;
+                let a = self.pop()?;
+                if a != 0 {
+                    return Ok(Flow::Goto(self.relative_target(program, offset)?));
+                }
+            }
+        }
+        Ok(Flow::Next)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexError {
+    UnknownToken(Span, String), // a word that is neither an op, a literal nor a label
+    BadLabel(Span, String),     // a label whose name is not a valid label number
+}
+
+impl core::fmt::Display for LexError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            LexError::UnknownToken(span, token) => {
+                write!(f, "{}:{}: unknown token `{}`", span.line, span.col, token)
+            }
+            LexError::BadLabel(span, token) => {
+                write!(f, "{}:{}: bad label `{}`", span.line, span.col, token)
+            }
+        }
+    }
+}
+
+impl core::error::Error for LexError {}
+
+/// Maps every label id defined in `program` to the index of its
+/// `Op::Label`. Labels have to be unique, see `VM::prepare`; for a label
+/// defined more than once anyway this is the last definition.
+pub fn labels(program: &[Op]) -> BTreeMap<usize, usize> {
+    let mut labels = BTreeMap::new();
+    for (i, op) in program.iter().enumerate() {
+        if let Op::Label(label) = op {
+            labels.insert(*label, i);
+        }
+    }
+    labels
+}
+
+// Checks that label ids are unique and contiguous from zero, as `VM::prepare`
+// requires, and otherwise returns its error with the index of the offending
+// label
+pub(crate) fn check_labels(program: &[Op]) -> Result<(), (VmError, usize)> {
+    let mut defined = BTreeSet::new();
+    for (i, op) in program.iter().enumerate() {
+        if let Op::Label(label) = op {
+            if !defined.insert(*label) {
+                return Err((VmError::DuplicateLabel, i));
+            }
+        }
+    }
+    for (expected, (label, i)) in labels(program).into_iter().enumerate() {
+        if label != expected {
+            return Err((VmError::BadLabel, i));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunError {
+    Lex(LexError),
+    Vm(VmError),
+}
+
+impl From<LexError> for RunError {
+    fn from(err: LexError) -> Self {
+        RunError::Lex(err)
+    }
+}
+
+impl From<VmError> for RunError {
+    fn from(err: VmError) -> Self {
+        RunError::Vm(err)
+    }
+}
+
+impl core::fmt::Display for RunError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            RunError::Lex(err) => err.fmt(f),
+            RunError::Vm(err) => err.fmt(f),
+        }
+    }
+}
+
+impl core::error::Error for RunError {}
+
+/// Runs `source` with `input` as the values for `Op::Read`, and returns
+/// every value the program wrote with `Op::Put`.
+#[cfg(feature = "std")]
+pub fn run_io(source: &str, input: &[i64]) -> Result<Vec<i64>, RunError> {
+    let assembly = Lexer::assemble(source)?;
+    let input: Vec<String> = input.iter().map(|x| x.to_string()).collect();
+
+    let mut vm = VM::new();
+    vm.set_data(assembly.data);
+    vm.set_input(std::io::Cursor::new(input.join(" ")));
+    vm.set_output(std::io::sink());
+    vm.record_outputs();
+    vm.execute(&assembly.ops)?;
+    Ok(vm.take_outputs())
+}
+
+impl core::fmt::Display for Op {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Op::Lit(x) => write!(f, "{}", x),
+            Op::Const(index) => write!(f, "${}", index),
+            Op::Label(label) => write!(f, "{}:", label),
+            Op::Switch(targets) => {
+                let targets: Vec<String> = targets.iter().map(|label| label.to_string()).collect();
+                write!(f, "switch[{}]", targets.join(","))
+            }
+            Op::Syscall(id) => write!(f, "syscall[{}]", id),
+            Op::ToReg(reg) => write!(f, ">r{}", reg),
+            Op::FromReg(reg) => write!(f, "r{}>", reg),
+            op => f.write_str(op.name()),
+        }
+    }
+}
+
+/// Turns `program` back into source that lexes to the same ops. Ops are
+/// written in their canonical form, see `Op::name`, and every label starts a
+/// new line.
+pub fn disassemble(program: &[Op]) -> String {
+    disassemble_with_comments(program, &[])
+}
+
+/// Like `disassemble`, but also writes each comment, as found in
+/// `Assembly::comments`, on its own line before the op it precedes.
+pub fn disassemble_with_comments(program: &[Op], comments: &[(usize, String)]) -> String {
+    disassemble_source(program, comments, &Symbols::default())
+}
+
+// Writes the source for `program`, with the names in `symbols` in place of
+// the numbers they stand for
+fn disassemble_source(program: &[Op], comments: &[(usize, String)], symbols: &Symbols) -> String {
+    let mut source = String::new();
+    let mut comments = comments.iter().peekable();
+    for (i, op) in program.iter().enumerate() {
+        let mut commented = false;
+        while let Some((_, comment)) = comments.next_if(|(at, _)| *at <= i) {
+            if !source.is_empty() {
+                source.push('\n');
+            }
+            source.push('#');
+            source.push_str(comment);
+            commented = true;
+        }
+        if !source.is_empty() {
+            source.push(if commented || matches!(op, Op::Label(_)) {
+                '\n'
+            } else {
+                ' '
+            });
+        }
+        match symbolic(op, program.get(i + 1), symbols) {
+            Some(name) => source.push_str(&name),
+            None => source.push_str(&op.to_string()),
+        }
+    }
+    for (_, comment) in comments {
+        if !source.is_empty() {
+            source.push('\n');
+        }
+        source.push('#');
+        source.push_str(comment);
+    }
+    source
+}
+
+/// Like `disassemble`, but starts with a `#@name` comment for every name in
+/// `symbols`, so that `Lexer::assemble` gives back the symbols along with
+/// the ops, and writes the names where `color::listing_with_symbols` shows
+/// them: for label definitions, for literal labels right before an op that
+/// jumps to or traps on them, and for literal addresses right before a
+/// `Load` or `Store`.
+pub fn disassemble_with_symbols(program: &[Op], symbols: &Symbols) -> String {
+    let names = symbols
+        .addresses
+        .iter()
+        .map(|(address, name)| format!("@name {} {}", name, address))
+        .chain(
+            symbols
+                .labels
+                .iter()
+                .map(|(label, name)| format!("@name {} {}:", name, label)),
+        );
+    let comments: Vec<_> = names.map(|name| (0, name)).collect();
+    disassemble_source(program, &comments, symbols)
+}
+
+// The name `op` stands for when followed by `next`, if it has one
+pub(crate) fn symbolic(op: &Op, next: Option<&Op>, symbols: &Symbols) -> Option<String> {
+    let labelled = matches!(
+        next,
+        Some(
+            Op::Jmp
+                | Op::CJmp
+                | Op::JmpEq
+                | Op::JmpNe
+                | Op::JmpLt
+                | Op::JmpGt
+                | Op::JmpNeg
+                | Op::JmpPos
+                | Op::JmpZero
+                | Op::Trap
+        )
+    );
+    match op {
+        Op::Label(label) => Some(format!("{}:", symbols.labels.get(label)?)),
+        Op::Lit(x) if labelled => symbols.labels.get(&usize::try_from(*x).ok()?).cloned(),
+        Op::Lit(x) if matches!(next, Some(Op::Load | Op::Store)) => {
+            symbols.addresses.get(&usize::try_from(*x).ok()?).cloned()
+        }
+        _ => None,
+    }
+}
+
+/// Counts how often each kind of op occurs in `program`, keyed by
+/// `Op::name`.
+#[cfg(feature = "std")]
+pub fn opcode_histogram(program: &[Op]) -> HashMap<&'static str, usize> {
+    let mut histogram = HashMap::new();
+    for op in program {
+        *histogram.entry(op.name()).or_insert(0) += 1;
+    }
+    histogram
+}
+
+/// Source location of a token, both 1-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+/// The result of lexing a source file with `Lexer::assemble`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    pub ops: Vec<Op>,
+    pub spans: Vec<Span>,               // source location of each op
+    pub data: Vec<i64>,                 // the constant pool, see `VM::set_data`
+    pub warnings: Vec<LexError>,        // words skipped in `LexMode::Lenient`
+    pub comments: Vec<(usize, String)>, // text after each `#`, with the index of the next op
+    pub symbols: Symbols,               // names given by `#@name` comments
+}
+
+/// Names for memory addresses and labels, given in the source by comments
+/// like `#@name counter 3` for address 3 and `#@name loop 0:` for label 0,
+/// for listings to show instead of the numbers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+    pub addresses: BTreeMap<usize, String>,
+    pub labels: BTreeMap<usize, String>,
+}
+
+/// How the lexer treats words it does not understand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LexMode {
+    Strict,  // fail on the first bad word
+    Lenient, // skip bad words and record them as warnings
+}
+
+/// A word of source, see `Lexer::tokenize`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Comment(Span, String), // the text after a `#`, to the end of the line
+    Word(Span, String),    // an op, a literal or a label, or a constant in a `.data` section
+    Data(Span),            // `.data`, which starts a section of constants
+    Text(Span),            // `.text`, which goes back to ops
+}
+
+/// The words the lexer turns into ops, see `Lexer::codegen_with_map`.
+///
+/// The default map understands the standard words. Words added with
+/// `insert` take precedence over them, and `TokenMap::empty` starts without
+/// them for a completely different syntax. Literals, labels and the other
+/// forms with an argument, such as `>r0`, `$0` and `switch[0]`, are always
+/// understood.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenMap {
+    words: BTreeMap<String, Op>,
+    standard: bool, // whether the standard words are understood
+}
+
+impl Default for TokenMap {
+    fn default() -> Self {
+        TokenMap {
+            words: BTreeMap::new(),
+            standard: true,
+        }
+    }
+}
+
+impl TokenMap {
+    /// A map without any words, not even the standard ones.
+    pub fn empty() -> TokenMap {
+        TokenMap {
+            words: BTreeMap::new(),
+            standard: false,
+        }
+    }
+
+    /// Makes `word` mean `op`, in place of any standard meaning it has.
+    pub fn insert(&mut self, word: &str, op: Op) {
+        self.words.insert(word.to_string(), op);
+    }
+
+    /// The op `word` means, if it is one of the words in the map.
+    pub fn get(&self, word: &str) -> Option<Op> {
+        match self.words.get(word) {
+            Some(op) => Some(op.clone()),
+            None if self.standard => Lexer::word(word),
+            None => None,
+        }
+    }
+}
+
+pub struct Lexer {}
+
+impl Lexer {
+    pub fn codegen(program: &str) -> Result<Vec<Op>, LexError> {
+        Ok(Self::codegen_with_spans(program)?.0)
+    }
+
+    /// Like `codegen`, but also returns the source span of every op so that
+    /// runtime errors can be traced back to the source.
+    pub fn codegen_with_spans(program: &str) -> Result<(Vec<Op>, Vec<Span>), LexError> {
+        let assembly = Self::assemble(program)?;
+        Ok((assembly.ops, assembly.spans))
+    }
+
+    /// Like `codegen`, but with the words in `map` instead of the standard
+    /// ones.
+    pub fn codegen_with_map(program: &str, map: &TokenMap) -> Result<Vec<Op>, LexError> {
+        let tokens = Self::tokenize(program);
+        Ok(Self::assemble_tokens_with_map(&tokens, LexMode::Strict, map)?.ops)
+    }
+
+    /// Lexes a whole source file, including its `.data` sections.
+    ///
+    /// Words between `.data` and `.text` (or the end of the source) are
+    /// integers appended to the read-only constant pool instead of ops. The
+    /// pool is indexed from zero in order of appearance, and `$n` pushes the
+    /// constant at index n.
+    ///
+    /// A word starting with `#` begins a comment that runs to the end of the
+    /// line. Comments are kept in `Assembly::comments` so that
+    /// `disassemble_with_comments` can write them back. A comment of the form
+    /// `#@name <name> <address>` or `#@name <name> <label>:` also names the
+    /// address or label in `Assembly::symbols`; the name may be any word that
+    /// is not an integer. After the comment `<name>:` defines the label and
+    /// `<name>` pushes the address or the label id, unless the name is
+    /// already a word of its own.
+    pub fn assemble(program: &str) -> Result<Assembly, LexError> {
+        Self::assemble_with_mode(program, LexMode::Strict)
+    }
+
+    /// Like `assemble`, but in `LexMode::Lenient` words that do not lex are
+    /// skipped and reported in `Assembly::warnings` instead of failing.
+    pub fn assemble_with_mode(program: &str, mode: LexMode) -> Result<Assembly, LexError> {
+        Self::assemble_tokens(&Self::tokenize(program), mode)
+    }
+
+    /// Splits source into tokens without looking at what the words mean, so
+    /// that tools such as highlighters can work on source that does not
+    /// lex. `assemble_tokens` turns the tokens into ops.
+    pub fn tokenize(program: &str) -> Vec<Token> {
+        Self::words(program)
+            .map(|(span, x)| match x {
+                ".data" => Token::Data(span),
+                ".text" => Token::Text(span),
+                x => match x.strip_prefix('#') {
+                    Some(comment) => Token::Comment(span, comment.to_string()),
+                    None => Token::Word(span, x.to_string()),
+                },
+            })
+            .collect()
+    }
+
+    /// Generates the ops for tokens from `tokenize`, like
+    /// `assemble_with_mode` does for source.
+    pub fn assemble_tokens(tokens: &[Token], mode: LexMode) -> Result<Assembly, LexError> {
+        Self::assemble_tokens_with_map(tokens, mode, &TokenMap::default())
+    }
+
+    /// Like `assemble_tokens`, but with the words in `map` instead of the
+    /// standard ones.
+    pub fn assemble_tokens_with_map(
+        tokens: &[Token],
+        mode: LexMode,
+        map: &TokenMap,
+    ) -> Result<Assembly, LexError> {
+        let mut ops = Vec::new();
+        let mut warnings = Vec::new();
+        let mut comments = Vec::new();
+        let mut symbols = Symbols::default();
+        let mut spans = Vec::new();
+        let mut data = Vec::new();
+        let mut in_data = false;
+        for token in tokens {
+            let (span, x) = match token {
+                Token::Data(_) => {
+                    in_data = true;
+                    continue;
+                }
+                Token::Text(_) => {
+                    in_data = false;
+                    continue;
+                }
+                Token::Comment(span, comment) => {
+                    if comment.starts_with("@name") && Self::symbol(comment, &mut symbols).is_none()
+                    {
+                        let err = LexError::UnknownToken(*span, format!("#{}", comment));
+                        match mode {
+                            LexMode::Lenient => warnings.push(err),
+                            LexMode::Strict => return Err(err),
+                        }
+                    }
+                    comments.push((ops.len(), comment.clone()));
+                    continue;
+                }
+                Token::Word(span, x) => (*span, x.as_str()),
+            };
+            if in_data {
+                match Self::int(x) {
+                    Some(x) => data.push(x),
+                    None if mode == LexMode::Lenient => {
+                        warnings.push(LexError::UnknownToken(span, x.to_string()))
+                    }
+                    None => return Err(LexError::UnknownToken(span, x.to_string())),
+                }
+                continue;
+            }
+            let op = match Self::op(span, x, map) {
+                Ok(op) => op,
+                Err(err) => match Self::named(x, &symbols) {
+                    Some(op) => op,
+                    None if mode == LexMode::Lenient => {
+                        warnings.push(err);
+                        continue;
+                    }
+                    None => return Err(err),
+                },
+            };
+            ops.push(op);
+            spans.push(span);
+        }
+        Ok(Assembly {
+            ops,
+            spans,
+            data,
+            warnings,
+            comments,
+            symbols,
+        })
+    }
+
+    // Adds the name given by a `#@name` comment to the symbols
+    fn symbol(comment: &str, symbols: &mut Symbols) -> Option<()> {
+        let mut words = comment.split_whitespace();
+        let (Some("@name"), Some(name), Some(target), None) =
+            (words.next(), words.next(), words.next(), words.next())
+        else {
+            return None;
+        };
+        if Self::int(name).is_some() {
+            return None;
+        }
+        match target.strip_suffix(':') {
+            Some(label) => symbols.labels.insert(label.parse().ok()?, name.to_string()),
+            None => symbols
+                .addresses
+                .insert(target.parse().ok()?, name.to_string()),
+        };
+        Some(())
+    }
+
+    // The op a name from a `#@name` comment stands for, `<name>:` for the
+    // label definition and `<name>` for the address or label id
+    fn named(word: &str, symbols: &Symbols) -> Option<Op> {
+        let find = |names: &BTreeMap<usize, String>, word: &str| {
+            names
+                .iter()
+                .find(|(_, name)| *name == word)
+                .map(|(n, _)| *n)
+        };
+        match word.strip_suffix(':') {
+            Some(name) => find(&symbols.labels, name).map(Op::Label),
+            None => find(&symbols.addresses, word)
+                .or_else(|| find(&symbols.labels, word))
+                .map(|n| Op::Lit(n as i64)),
+        }
+    }
+
+    // The op named by one of the standard words
+    fn word(word: &str) -> Option<Op> {
+        let op = match word {
+            "+" | "add" => Op::Add,
+            "-" | "sub" => Op::Sub,
+            "*" | "mul" => Op::Mul,
+            "/" | "div" => Op::Div,
+            "floordiv" => Op::FloorDiv,
+            "sqrt" => Op::Sqrt,
+            "fact" => Op::Fact,
+            "maxint" => Op::MaxInt,
+            "minint" => Op::MinInt,
+            "gcd" => Op::Gcd,
+            "absdiff" => Op::AbsDiff,
+            "divides" => Op::Divides,
+            "popcount" => Op::PopCount,
+            "parity" => Op::Parity,
+            "clz" => Op::Clz,
+            "ctz" => Op::Ctz,
+            "ispow2" => Op::IsPow2,
+            "digitval" => Op::DigitVal,
+            "rotl" => Op::RotL,
+            "rotr" => Op::RotR,
+            "1+" => Op::Inc,
+            "1-" => Op::Dec,
+            "1-dup" => Op::DecDup,
+            "+s" => Op::SatAdd,
+            "-s" => Op::SatSub,
+            "*s" => Op::SatMul,
+            "load" => Op::Load,
+            "store" => Op::Store,
+            "loadb" => Op::LoadByte,
+            "storeb" => Op::StoreByte,
+            "memcopy" => Op::MemCopy,
+            "memfill" => Op::MemFill,
+            "memswap" => Op::MemSwap,
+            "memcount" => Op::MemCount,
+            "memsort" => Op::MemSort,
+            "memhash" => Op::MemHash,
+            "memeq" => Op::MemEq,
+            "memmax" => Op::MemMax,
+            "memmin" => Op::MemMin,
+            "todigits" => Op::ToDigits,
+            "cas" => Op::Cas,
+            "start" => Op::Start,
+            "jmp" => Op::Jmp,
+            "cjmp" => Op::CJmp,
+            "jeq" => Op::JmpEq,
+            "jne" => Op::JmpNe,
+            "jlt" => Op::JmpLt,
+            "jgt" => Op::JmpGt,
+            "jneg" => Op::JmpNeg,
+            "jpos" => Op::JmpPos,
+            "jz" => Op::JmpZero,
+            "rjmp" => Op::RJmp,
+            "rcjmp" => Op::RCJmp,
+            "." | "put" => Op::Put,
+            "cr" => Op::CR,
+            ".cr" => Op::PutCr,
+            "putstr" => Op::PutStrMem,
+            "," | "read" => Op::Read,
+            "readall" => Op::ReadAll,
+            "arg" => Op::Arg,
+            "dup" => Op::Dup,
+            "swap" => Op::Swap,
+            "tuck" => Op::Tuck,
+            "nip" => Op::Nip,
+            "dupall" => Op::DupAll,
+            "dropn" => Op::DropN,
+            "reversen" => Op::ReverseN,
+            "=" | "eq" => Op::Eq,
+            "<" | "lt" => Op::Lt,
+            ">" | "gt" => Op::Gt,
+            "select" => Op::Select,
+            "clamp" => Op::Clamp,
+            "?" => Op::Dump,
+            "halt" => Op::Halt,
+            "exit" => Op::Exit,
+            "trap" => Op::Trap,
+            "proglen" => Op::ProgLen,
+            "here" => Op::Here,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    fn op(span: Span, lit: &str, map: &TokenMap) -> Result<Op, LexError> {
+        let op = match map.get(lit) {
+            Some(op) => op,
+            None => {
+                if let Some(x) = Self::int(lit) {
+                    Op::Lit(x)
+                } else if let Some(reg) = Self::register(lit.strip_prefix(">r")) {
+                    Op::ToReg(reg)
+                } else if let Some(reg) =
+                    Self::register(lit.strip_prefix('r').and_then(|r| r.strip_suffix('>')))
+                {
+                    Op::FromReg(reg)
+                } else if let Some(index) = lit.strip_prefix('$') {
+                    match index.parse() {
+                        Ok(index) => Op::Const(index),
+                        Err(_) => return Err(LexError::UnknownToken(span, lit.to_string())),
+                    }
+                } else if let Some(id) = lit
+                    .strip_prefix("syscall[")
+                    .and_then(|id| id.strip_suffix(']'))
+                {
+                    match id.parse() {
+                        Ok(id) => Op::Syscall(id),
+                        Err(_) => return Err(LexError::UnknownToken(span, lit.to_string())),
+                    }
+                } else if let Some(targets) = lit
+                    .strip_prefix("switch[")
+                    .and_then(|targets| targets.strip_suffix(']'))
+                {
+                    let targets: Result<_, _> = targets
+                        .split(',')
+                        .filter(|label| !label.is_empty())
+                        .map(str::parse)
+                        .collect();
+                    match targets {
+                        Ok(targets) => Op::Switch(targets),
+                        Err(_) => return Err(LexError::BadLabel(span, lit.to_string())),
+                    }
+                } else {
+                    let Some(label) = lit.strip_suffix(':') else {
+                        return Err(LexError::UnknownToken(span, lit.to_string()));
+                    };
+                    match label.parse() {
+                        Ok(label) => Op::Label(label),
+                        Err(_) => return Err(LexError::BadLabel(span, lit.to_string())),
+                    }
+                }
+            }
+        };
+        Ok(op)
+    }
+
+    // Parses the number of a register, `>r3` or `r3>`
+    fn register(reg: Option<&str>) -> Option<u8> {
+        reg?.parse().ok().filter(|reg| (*reg as usize) < REGISTERS)
+    }
+
+    // Parses an integer literal, which may separate its digits with
+    // underscores like Rust literals, but not start or end with one
+    fn int(word: &str) -> Option<i64> {
+        let digits = word.strip_prefix(['-', '+']).unwrap_or(word);
+        if digits.starts_with('_') || digits.ends_with('_') {
+            return None;
+        }
+        word.replace('_', "").parse().ok()
+    }
+
+    // Splits the source on whitespace, keeping track of where each word
+    // starts. A `#` at the start of a word makes the rest of the line a
+    // single word
+    fn words(program: &str) -> impl Iterator<Item = (Span, &str)> {
+        program.lines().enumerate().flat_map(|(line, text)| {
+            let mut words = Vec::new();
+            let mut start = None;
+            for (col, (i, c)) in text.char_indices().enumerate() {
+                match (c.is_whitespace(), start) {
+                    (false, None) if c == '#' => {
+                        start = Some((col, i));
+                        break;
+                    }
+                    (false, None) => start = Some((col, i)),
+                    (true, Some((col, s))) => {
+                        words.push((
+                            Span {
+                                line: line + 1,
+                                col: col + 1,
+                            },
+                            &text[s..i],
+                        ));
+                        start = None;
+                    }
+                    _ => (),
+                }
+            }
+            if let Some((col, s)) = start {
+                words.push((
+                    Span {
+                        line: line + 1,
+                        col: col + 1,
+                    },
+                    &text[s..],
+                ));
+            }
+            words
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    // TODO: prop tests
+
+    // Small xorshift generator so the randomized tests need no dependencies
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    // Output sink that can still be read after the VM took ownership of it
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuf {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    impl core::fmt::Write for SharedBuf {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            self.0.borrow_mut().extend_from_slice(s.as_bytes());
+            Ok(())
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn fuzz_iterations() -> usize {
+        1000
+    }
+
+    #[cfg(feature = "std")]
+    fn fuzz_iterations() -> usize {
+        std::env::var("BYTTI_FUZZ_ITERS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(1000)
+    }
+
+    #[test]
+    fn addition() {
+        let mut vm = VM::new();
+        let program = vec![Op::Lit(1), Op::Lit(2), Op::Add];
+
+        let top = vm.execute(&program).unwrap().unwrap();
+        assert_eq!(top, 3)
+    }
+
+    #[test]
+    fn increment_decrement() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("41 1+ 1+ 1-").unwrap();
+
+        let top = vm.execute(&program).unwrap().unwrap();
+        assert_eq!(top, 42)
+    }
+
+    #[test]
+    fn increment_overflow() {
+        let mut vm = VM::new();
+        let program = vec![Op::Lit(i64::MAX), Op::Inc];
+        assert_eq!(vm.execute(&program), Err(VmError::Overflow));
+
+        let mut vm = VM::new();
+        let program = vec![Op::Lit(i64::MIN), Op::Dec];
+        assert_eq!(vm.execute(&program), Err(VmError::Overflow));
+    }
+
+    #[test]
+    fn error_span() {
+        let mut vm = VM::new();
+        let (program, spans) = Lexer::codegen_with_spans("1 2 +\n  0 5 /").unwrap();
+
+        let err = vm.execute(&program).unwrap_err();
+        assert_eq!(err, VmError::DivideByZero);
+        assert_eq!(spans[vm.ip], Span { line: 2, col: 7 });
+        assert_eq!(vm.format_error(&err, &spans), "2:7: divide by zero");
+    }
+
+    #[test]
+    fn fault() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 2 3 4 5 6 7 8 9 0 store -1 load").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+        let fault = vm.fault().unwrap();
+        assert_eq!(fault.error, VmError::BadMemoryAccess);
+        assert_eq!(fault.at, 12);
+        assert_eq!(fault.stack, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(
+            fault.to_string(),
+            "instruction 12: bad memory access (stack [1, 2, 3, 4, 5, 6, 7, 8])"
+        );
+
+        // The operands the op popped are gone
+        let mut vm = VM::new();
+        let program = Lexer::codegen("2 1 load").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+        assert_eq!(vm.fault().unwrap().stack, [2]);
+
+        // Caught errors are not faults
+        let program = Lexer::codegen("0 trap -1 load 0:").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(4)));
+        assert_eq!(vm.fault(), None);
+    }
+
+    #[test]
+    fn underflow_hook() {
+        let dumps = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new();
+        let sink = dumps.clone();
+        vm.set_underflow_hook(move |vm| {
+            let at = vm.fault().unwrap().at;
+            sink.borrow_mut().push((at, vm.dump_string()));
+        });
+
+        let program = Lexer::codegen("1 0 store 2 + +").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::StackUnderflow));
+        assert_eq!(
+            *dumps.borrow(),
+            [(4, "stack []\nmemory [1]\njmp table []\n".to_string())]
+        );
+
+        // Only underflows call it
+        let program = Lexer::codegen("0 1 /").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::DivideByZero));
+        assert_eq!(dumps.borrow().len(), 1);
+    }
+
+    #[test]
+    fn memcopy_overlapping() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen(
+            "1 0 store 2 1 store 3 2 store 4 3 store 5 4 store
+             1 0 4 memcopy",
+        )
+        .unwrap();
+
+        vm.execute(&program).unwrap();
+        assert_eq!(vm.memory, [1, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn memfill() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("9 0 store 0 4 7 memfill 1 load 3 load").unwrap();
+
+        let top = vm.execute(&program).unwrap().unwrap();
+        assert_eq!(top, 7);
+        assert_eq!(vm.stack, [7]);
+        assert_eq!(vm.memory, [7, 7, 7, 7]);
+    }
+
+    #[test]
+    fn memcount() {
+        let mut vm = VM::new();
+        vm.memory = vec![3, 1, 3, 3, 2, 3];
+        let program = Lexer::codegen("0 6 3 memcount 1 3 3 memcount 2 0 3 memcount").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(0)));
+        assert_eq!(vm.stack, [4, 2]);
+
+        let program = Lexer::codegen("4 3 3 memcount").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+        let program = Lexer::codegen("-1 2 3 memcount").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+    }
+
+    #[test]
+    fn memsort() {
+        let mut vm = VM::new();
+        vm.memory = vec![9, 5, -2, 7, 5, 0, 1];
+        let program = Lexer::codegen("1 5 memsort").unwrap();
+        assert_eq!(vm.execute(&program), Ok(None));
+        assert_eq!(vm.memory, [9, -2, 0, 5, 5, 7, 1]);
+
+        let program = Lexer::codegen("3 5 memsort").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+        let program = Lexer::codegen("-1 2 memsort").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+    }
+
+    #[test]
+    fn memhash() {
+        let mut vm = VM::new();
+        vm.memory = vec![7, 1, 2, 3, 7];
+        let program = Lexer::codegen("1 3 memhash 0 0 memhash").unwrap();
+        assert_eq!(
+            vm.execute(&program),
+            Ok(Some(0xcbf2_9ce4_8422_2325_u64 as i64))
+        );
+        assert_eq!(vm.stack, [0xda2b_fb22_5e0d_1f05_u64 as i64]);
+
+        vm.memory[2] = 4;
+        let program = Lexer::codegen("1 3 memhash").unwrap();
+        assert_ne!(
+            vm.execute(&program),
+            Ok(Some(0xda2b_fb22_5e0d_1f05_u64 as i64))
+        );
+
+        let program = Lexer::codegen("3 3 memhash").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn dump_memory_region() {
+        let mut vm = VM::new();
+        vm.memory = vec![0, 1, 2, 3, 4, 255, -1, 8];
+
+        let mut out = Vec::new();
+        vm.dump_memory_region(1, 6, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "00000001: 0000000000000001 0000000000000002 0000000000000003 0000000000000004\n\
+             00000005: 00000000000000ff ffffffffffffffff\n"
+        );
+    }
+
+    #[test]
+    fn preallocated_memory() {
+        let mut vm = VM::with_memory_size(16);
+        let program = Lexer::codegen("5 3 store 10 load").unwrap();
+
+        let top = vm.execute(&program).unwrap().unwrap();
+        assert_eq!(top, 0);
+        assert_eq!(vm.memory.len(), 16);
+        assert_eq!(vm.memory[3], 5);
+    }
+
+    #[test]
+    fn zero_filled_memory() {
+        let mut vm = VM::new();
+        vm.set_zero_filled(1000);
+        let program = Lexer::codegen("999 load 5 10 store 3 load 10 load").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(5)));
+        assert_eq!(vm.stack, [0, 0]);
+        assert_eq!(vm.memory.len(), 11);
+
+        let program = Lexer::codegen("1000 load").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+        let program = Lexer::codegen("1 1001 store").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+
+        let mut vm = VM::new();
+        let program = Lexer::codegen("0 load").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+    }
+
+    #[test]
+    fn lex_errors() {
+        assert_eq!(
+            Lexer::codegen("1 2\n  foo +"),
+            Err(LexError::UnknownToken(
+                Span { line: 2, col: 3 },
+                "foo".to_string()
+            ))
+        );
+        assert_eq!(
+            Lexer::codegen("-1:"),
+            Err(LexError::BadLabel(
+                Span { line: 1, col: 1 },
+                "-1:".to_string()
+            ))
+        );
+    }
+
+    // Feeds random byte strings, biased towards real tokens, to the lexer. A
+    // panic fails the test; any input must lex to either ops or a LexError.
+    // Run a longer session with e.g.
+    // `BYTTI_FUZZ_ITERS=1000000 cargo test --release fuzz_lexer`.
+    #[test]
+    fn fuzz_lexer() {
+        const PIECES: &[&str] = &[
+            "+",
+            "-",
+            "1+",
+            "1-",
+            "load",
+            "store",
+            "cjmp",
+            "0:",
+            "1:",
+            ":",
+            "-",
+            "9",
+            "_",
+            "x",
+            " ",
+            "\n",
+            "\t",
+            "\u{e9}",
+            "99999999999999999999",
+        ];
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..fuzz_iterations() {
+            let mut bytes = Vec::new();
+            for _ in 0..rng.below(16) {
+                if rng.below(2) == 0 {
+                    bytes.push(rng.next() as u8);
+                } else {
+                    bytes.extend_from_slice(PIECES[rng.below(PIECES.len())].as_bytes());
+                }
+            }
+            let _ = Lexer::codegen(&String::from_utf8_lossy(&bytes));
+        }
+    }
+
+    #[test]
+    fn memswap() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("10 0 store 20 1 store 0 1 memswap 0 load 1 load").unwrap();
+
+        vm.execute(&program).unwrap();
+        assert_eq!(vm.stack, [20]);
+        assert_eq!(vm.memory, [20, 10]);
+
+        let mut vm = VM::new();
+        let program = Lexer::codegen("10 0 store 0 1 memswap").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+    }
+
+    // Obviously correct evaluator for straight-line programs, used as the
+    // oracle for `differential`. Binary ops compute `top op second`, like the
+    // VM.
+    fn reference(program: &[Op]) -> Result<Vec<i64>, VmError> {
+        let mut stack: Vec<i64> = Vec::new();
+        for op in program {
+            let n = stack.len();
+            let needed = match op {
+                Op::Lit(_) => 0,
+                Op::Inc | Op::Dec | Op::Dup => 1,
+                _ => 2,
+            };
+            if n < needed {
+                return Err(VmError::StackUnderflow);
+            }
+            match op {
+                Op::Lit(x) => stack.push(*x),
+                Op::Inc | Op::Dec => {
+                    let x = if *op == Op::Inc {
+                        stack[n - 1].checked_add(1)
+                    } else {
+                        stack[n - 1].checked_sub(1)
+                    };
+                    stack[n - 1] = x.ok_or(VmError::Overflow)?;
+                }
+                Op::Dup => stack.push(stack[n - 1]),
+                Op::Swap => stack.swap(n - 1, n - 2),
+                _ => {
+                    let (a, b) = (stack[n - 1], stack[n - 2]);
+                    stack.truncate(n - 2);
+                    let x = match op {
+                        Op::Add => a.checked_add(b),
+                        Op::Sub => a.checked_sub(b),
+                        Op::Mul => a.checked_mul(b),
+                        Op::Div if b == 0 => return Err(VmError::DivideByZero),
+                        Op::Div => a.checked_div(b),
+                        Op::Eq => Some((a == b) as i64),
+                        Op::Lt => Some((a < b) as i64),
+                        Op::Gt => Some((a > b) as i64),
+                        _ => unreachable!(),
+                    };
+                    stack.push(x.ok_or(VmError::Overflow)?);
+                }
+            }
+        }
+        Ok(stack)
+    }
+
+    // Runs random straight-line programs through both the VM and `reference`
+    // and checks that they agree on the final stack or on the error.
+    #[test]
+    fn differential() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for _ in 0..fuzz_iterations() {
+            let mut program = Vec::new();
+            for _ in 0..rng.below(12) {
+                program.push(match rng.below(22) {
+                    0 => Op::Add,
+                    1 => Op::Sub,
+                    2 => Op::Mul,
+                    3 => Op::Div,
+                    4 => Op::Inc,
+                    5 => Op::Dec,
+                    6 => Op::Dup,
+                    7 => Op::Swap,
+                    8 => Op::Eq,
+                    9 => Op::Lt,
+                    10 => Op::Gt,
+                    _ => match rng.below(8) {
+                        0 => Op::Lit(i64::MAX),
+                        1 => Op::Lit(i64::MIN),
+                        _ => Op::Lit(rng.below(11) as i64 - 5),
+                    },
+                });
+            }
+            let expected = reference(&program);
+
+            let mut vm = VM::new();
+            let actual = vm.execute(&program).map(|top| {
+                vm.stack.extend(top);
+                vm.stack.clone()
+            });
+            assert_eq!(actual, expected, "{:?}", program);
+        }
+    }
+
+    #[test]
+    fn trap_divide_by_zero() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen(
+            "0 trap
+             0 1 / .
+             halt
+             0: 100 +",
+        )
+        .unwrap();
+
+        let top = vm.execute(&program).unwrap().unwrap();
+        assert_eq!(top, 100 + VmError::DivideByZero.code());
+    }
+
+    #[test]
+    fn trap_ignores_underflow() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("0 trap + 0:").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::StackUnderflow));
+    }
+
+    #[test]
+    fn proglen() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 2 + proglen 0: halt").unwrap();
+
+        let top = vm.execute(&program).unwrap().unwrap();
+        assert_eq!(top, program.len() as i64);
+        assert_eq!(top, 6);
+    }
+
+    #[test]
+    fn entry_point() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen(
+            "0: 2 *
+                1 jmp
+             start
+                21 0 jmp
+             1: halt",
+        )
+        .unwrap();
+
+        let top = vm.execute(&program).unwrap().unwrap();
+        assert_eq!(top, 42);
+    }
+
+    #[test]
+    fn tuck() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 2 tuck").unwrap();
+
+        let top = vm.execute(&program).unwrap();
+        assert_eq!(top, Some(2));
+        assert_eq!(vm.stack, [2, 1]);
+
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 tuck").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::StackUnderflow));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn timed() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1000 0: 1- dup 0 cjmp").unwrap();
+
+        let (res, elapsed) = vm.execute_timed(&program);
+        assert_eq!(res, Ok(Some(0)));
+        assert!(!elapsed.is_zero());
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 0 store 2 3 + 0: 1 trap 4 * 1: 5 1 store").unwrap();
+
+        vm.prepare(&program).unwrap();
+        for _ in 0..5 {
+            vm.step(&program).unwrap();
+        }
+        let state = vm.snapshot();
+        assert_eq!(state.stack, [2, 3]);
+        assert_eq!(state.ip, 5);
+
+        while vm.step(&program).unwrap() {}
+        assert_eq!(vm.stack, [20]);
+        assert_eq!(vm.memory, [1, 5]);
+        assert_eq!(vm.trap, Some(11));
+
+        vm.restore(state.clone());
+        assert_eq!(vm.snapshot(), state);
+        while vm.step(&program).unwrap() {}
+        assert_eq!(vm.stack, [20]);
+    }
+
+    #[test]
+    fn here() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 2 here here 3 +").unwrap();
+
+        // The second `here` plus its distance to the end addresses the end
+        let top = vm.execute(&program).unwrap().unwrap();
+        assert_eq!(top, program.len() as i64);
+        assert_eq!(vm.stack, [1, 2, 2]);
+    }
+
+    #[test]
+    fn relative_jumps() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 3 rjmp 100 . 2").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(2)));
+        assert_eq!(vm.stack, [1]);
+
+        // Count down from 3, jumping back to the `1-` while non-zero
+        let mut vm = VM::new();
+        let program = Lexer::codegen("3 1- dup -3 rcjmp").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(0)));
+
+        let mut vm = VM::new();
+        let program = Lexer::codegen("0 1 rcjmp 4 rjmp").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadJump));
+
+        let mut vm = VM::new();
+        let program = Lexer::codegen("-2 rjmp").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadJump));
+    }
+
+    #[test]
+    fn div_by_zero_policy() {
+        let program = Lexer::codegen("0 7 / 0 -7 / 0 0 /").unwrap();
+
+        let mut vm = VM::new();
+        assert_eq!(vm.execute(&program), Err(VmError::DivideByZero));
+
+        let mut vm = VM::new();
+        vm.set_div_by_zero(DivByZero::Zero);
+        assert_eq!(vm.execute(&program), Ok(Some(0)));
+        assert_eq!(vm.stack, [0, 0]);
+
+        let mut vm = VM::new();
+        vm.set_div_by_zero(DivByZero::Saturate);
+        assert_eq!(vm.execute(&program), Ok(Some(0)));
+        assert_eq!(vm.stack, [i64::MAX, i64::MIN]);
+    }
+
+    #[test]
+    fn fused_compare_jumps() {
+        // Each program pushes 1 when the jump is taken and 0 when it falls through
+        let cases = [
+            ("3 3 0 jeq 0 halt 0: 1", 1),
+            ("3 4 0 jeq 0 halt 0: 1", 0),
+            ("3 4 0 jne 0 halt 0: 1", 1),
+            ("3 3 0 jne 0 halt 0: 1", 0),
+            ("4 3 0 jlt 0 halt 0: 1", 1),
+            ("3 4 0 jlt 0 halt 0: 1", 0),
+            ("3 4 0 jgt 0 halt 0: 1", 1),
+            ("4 3 0 jgt 0 halt 0: 1", 0),
+            ("-3 0 jneg 0 halt 0: 1", 1),
+            ("0 0 jneg 0 halt 0: 1", 0),
+            ("3 0 jneg 0 halt 0: 1", 0),
+            ("3 0 jpos 0 halt 0: 1", 1),
+            ("0 0 jpos 0 halt 0: 1", 0),
+            ("-3 0 jpos 0 halt 0: 1", 0),
+            ("0 0 jz 0 halt 0: 1", 1),
+            ("3 0 jz 0 halt 0: 1", 0),
+            ("-3 0 jz 0 halt 0: 1", 0),
+        ];
+        for (source, taken) in cases {
+            let mut vm = VM::new();
+            let program = Lexer::codegen(source).unwrap();
+            assert_eq!(vm.execute(&program), Ok(Some(taken)), "{}", source);
+            assert!(vm.stack.is_empty());
+        }
+
+        // Only the label and the two operands are consumed, taken or not
+        for source in ["7 8 3 3 0 jeq 0:", "7 8 3 4 0 jeq 0:"] {
+            let mut vm = VM::new();
+            let program = Lexer::codegen(source).unwrap();
+            assert_eq!(vm.execute(&program), Ok(Some(8)), "{}", source);
+            assert_eq!(vm.stack, [7], "{}", source);
+        }
+    }
+
+    #[test]
+    fn run_until_output() {
+        let program = Lexer::codegen("1 0: dup . 1+ dup 4 > 0 cjmp 9").unwrap();
+        let mut vm = VM::new();
+        vm.set_output(SharedBuf::default());
+        for expected in [Some(1), Some(2), Some(3), None] {
+            assert_eq!(vm.run_until_output(&program), Ok(expected));
+        }
+        assert_eq!(vm.stack, [4, 9]);
+
+        // Once it ended, the program starts over
+        vm.stack.clear();
+        assert_eq!(vm.run_until_output(&program), Ok(Some(1)));
+
+        // The program also ends when it halts after its last output
+        let program = Lexer::codegen("7 .cr halt 8 .").unwrap();
+        let mut vm = VM::new();
+        vm.set_output(SharedBuf::default());
+        assert_eq!(vm.run_until_output(&program), Ok(Some(7)));
+        assert_eq!(vm.run_until_output(&program), Ok(None));
+    }
+
+    #[test]
+    fn label_map() {
+        let program = Lexer::codegen("1 0: 2 2: 3 1: 4 0 jmp").unwrap();
+
+        let expected = BTreeMap::from([(0, 1), (1, 5), (2, 3)]);
+        assert_eq!(labels(&program), expected);
+
+        let mut vm = VM::new();
+        vm.prepare(&program).unwrap();
+        assert_eq!(vm.jump_table, [1, 5, 3]);
+
+        let program = Lexer::codegen("0: 2:").unwrap();
+        assert_eq!(vm.prepare(&program), Err(VmError::BadLabel));
+    }
+
+    #[test]
+    fn jump_table() {
+        let program = Lexer::codegen("1 0: 2 2: 3 1: 4 0 jmp 5 6").unwrap();
+        let mut vm = VM::new();
+        vm.prepare(&program).unwrap();
+        let expected: Vec<usize> = labels(&program).into_values().collect();
+        assert_eq!(vm.jump_table(), expected);
+
+        // Send label 0 to the `5` instead, so the jump skips over it
+        vm.set_jump_target(0, 9).unwrap();
+        while vm.step(&program).unwrap() {}
+        assert_eq!(vm.stack, [1, 2, 3, 4, 6]);
+        assert_eq!(vm.set_jump_target(3, 0), Err(VmError::BadLabel));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn histogram() {
+        let program = Lexer::codegen("1 2 + 0: dup 3 * . 0 jmp").unwrap();
+
+        let expected = HashMap::from([
+            ("lit", 4),
+            ("+", 1),
+            ("label", 1),
+            ("dup", 1),
+            ("*", 1),
+            (".", 1),
+            ("jmp", 1),
+        ]);
+        assert_eq!(opcode_histogram(&program), expected);
+    }
+
+    #[test]
+    fn constant_pool() {
+        let assembly = Lexer::assemble(
+            ".data 10 20
+             .text $1 $0 +
+             .data -5",
+        )
+        .unwrap();
+        assert_eq!(assembly.data, [10, 20, -5]);
+        assert_eq!(assembly.ops, [Op::Const(1), Op::Const(0), Op::Add]);
+
+        let mut vm = VM::new();
+        vm.set_data(assembly.data);
+        assert_eq!(vm.execute(&assembly.ops), Ok(Some(30)));
+
+        let program = Lexer::codegen("$3").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+    }
+
+    #[test]
+    fn sqrt() {
+        let cases = [
+            (0, 0),
+            (1, 1),
+            (49, 7),
+            (50, 7),
+            (63, 7),
+            (64, 8),
+            (i64::MAX, 3037000499),
+        ];
+        for (a, root) in cases {
+            let mut vm = VM::new();
+            let program = [Op::Lit(a), Op::Sqrt];
+            assert_eq!(vm.execute(&program), Ok(Some(root)), "sqrt {}", a);
+        }
+
+        let mut vm = VM::new();
+        let program = Lexer::codegen("-4 sqrt").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadArgument));
+    }
+
+    #[test]
+    fn factorial() {
+        for (n, fact) in [(0, 1), (1, 1), (5, 120), (20, 2432902008176640000)] {
+            let mut vm = VM::new();
+            let program = [Op::Lit(n), Op::Fact];
+            assert_eq!(vm.execute(&program), Ok(Some(fact)), "{}!", n);
+        }
+
+        let program = Lexer::codegen("-1 fact").unwrap();
+        assert_eq!(VM::new().execute(&program), Err(VmError::BadArgument));
+
+        let program = Lexer::codegen("21 fact").unwrap();
+        assert_eq!(VM::new().execute(&program), Err(VmError::Overflow));
+        let mut vm = VM::new();
+        vm.set_overflow_mode(OverflowMode::Saturate);
+        assert_eq!(vm.execute(&program), Ok(Some(i64::MAX)));
+        let mut vm = VM::new();
+        vm.set_overflow_mode(OverflowMode::Wrap);
+        let wrapped = (1..=21).fold(1i64, |x, i| x.wrapping_mul(i));
+        assert_eq!(vm.execute(&program), Ok(Some(wrapped)));
+        let program = Lexer::codegen("maxint fact").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(0)));
+    }
+
+    #[test]
+    fn divides() {
+        let cases = [
+            ("15 3 divides", 1),
+            ("15 4 divides", 0),
+            ("-15 5 divides", 1),
+            ("0 7 divides", 1),
+            ("minint -1 divides", 1),
+        ];
+        for (source, expected) in cases {
+            let mut vm = VM::new();
+            let program = Lexer::codegen(source).unwrap();
+            assert_eq!(vm.execute(&program), Ok(Some(expected)), "{}", source);
+        }
+
+        let mut vm = VM::new();
+        vm.set_div_by_zero(DivByZero::Zero);
+        let program = Lexer::codegen("15 0 divides").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::DivideByZero));
+    }
+
+    #[test]
+    fn gcd() {
+        let cases = [
+            (12, 18, 6),
+            (17, 5, 1),
+            (0, 7, 7),
+            (7, 0, 7),
+            (0, 0, 0),
+            (-12, 18, 6),
+            (12, -18, 6),
+            (-12, -18, 6),
+            (0, -7, 7),
+        ];
+        for (a, b, gcd) in cases {
+            let mut vm = VM::new();
+            let program = [Op::Lit(a), Op::Lit(b), Op::Gcd];
+            assert_eq!(vm.execute(&program), Ok(Some(gcd)), "gcd({}, {})", a, b);
+        }
+
+        // 2^63 does not fit in an i64
+        let mut vm = VM::new();
+        let program = [Op::Lit(i64::MIN), Op::Lit(0), Op::Gcd];
+        assert_eq!(vm.execute(&program), Err(VmError::Overflow));
+    }
+
+    #[test]
+    fn abs_diff() {
+        let cases = [
+            (3, 10, Ok(Some(7))),
+            (10, 3, Ok(Some(7))),
+            (-4, 4, Ok(Some(8))),
+            (5, 5, Ok(Some(0))),
+            (i64::MAX, 0, Ok(Some(i64::MAX))),
+            (i64::MAX, -1, Err(VmError::Overflow)),
+            (i64::MIN, i64::MAX, Err(VmError::Overflow)),
+            (i64::MIN, 0, Err(VmError::Overflow)),
+            (i64::MIN, -1, Ok(Some(i64::MAX))),
+        ];
+        for (a, b, expected) in cases {
+            let program = [Op::Lit(a), Op::Lit(b), Op::AbsDiff];
+            assert_eq!(VM::new().execute(&program), expected, "|{} - {}|", a, b);
+        }
+
+        let mut vm = VM::new();
+        vm.set_overflow_mode(OverflowMode::Saturate);
+        let program = [Op::Lit(i64::MIN), Op::Lit(i64::MAX), Op::AbsDiff];
+        assert_eq!(vm.execute(&program), Ok(Some(i64::MAX)));
+    }
+
+    #[test]
+    fn floor_division() {
+        // Like `/`, `floordiv` divides the top value by the one below it
+        let cases = [
+            ("-7 2 /", 0),
+            ("-7 2 floordiv", -1),
+            ("2 -7 /", -3),
+            ("2 -7 floordiv", -4),
+            ("2 7 /", 3),
+            ("2 7 floordiv", 3),
+            ("-2 -7 floordiv", 3),
+            ("2 -8 floordiv", -4),
+        ];
+        for (source, expected) in cases {
+            let mut vm = VM::new();
+            let program = Lexer::codegen(source).unwrap();
+            assert_eq!(vm.execute(&program), Ok(Some(expected)), "{}", source);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn echo() {
+        let program = "0: , 1 cjmp halt 1: . 0 jmp";
+        assert_eq!(run_io(program, &[1, 2, 3]), Ok(vec![1, 2, 3]));
+        assert_eq!(run_io(program, &[]), Ok(vec![]));
+        assert_eq!(
+            run_io("0 1 /", &[]),
+            Err(RunError::Vm(VmError::DivideByZero))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn read_and_write() {
+        let out = SharedBuf::default();
+        let mut vm = VM::new();
+        vm.set_input(std::io::Cursor::new(" 12\n-3  "));
+        vm.set_output(out.clone());
+        let program = Lexer::codegen(", . cr . cr , . cr . cr ,").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(0)));
+        assert_eq!(out.contents(), "1\n12\n1\n-3\n");
+
+        let mut vm = VM::new();
+        vm.set_input(std::io::Cursor::new("12x"));
+        let program = Lexer::codegen(",").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadInput));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    #[allow(deprecated)]
+    fn misspelled_aliases() {
+        let program = Lexer::codegen("1 2 +").unwrap();
+        assert_eq!(VM::new().excecute(&program), Ok(Some(3)));
+        assert_eq!(VM::new().excecute_timed(&program).0, Ok(Some(3)));
+        assert_eq!(VM::new().excecute_with_coverage(&program), Ok(vec![]));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn read_all() {
+        let mut vm = VM::with_memory_size(2);
+        vm.set_input(std::io::Cursor::new("hi\n"));
+        let program = Lexer::codegen("1 readall 0 readall").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(0)));
+        assert_eq!(vm.stack, [3]);
+        assert_eq!(vm.memory, [0, b'h' as i64, b'i' as i64, b'\n' as i64]);
+
+        let mut vm = VM::new();
+        vm.set_input(std::io::Cursor::new("x"));
+        let program = Lexer::codegen("1 readall").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+    }
+
+    #[test]
+    fn saturating_ops() {
+        let cases = [
+            ("maxint 1 +s", i64::MAX),
+            ("-1 minint +s", i64::MIN),
+            ("minint 1 -s", i64::MAX),
+            ("maxint -2 -s", i64::MIN),
+            ("2 maxint *s", i64::MAX),
+            ("-2 maxint *s", i64::MIN),
+            ("3 4 +s 2 -s 5 *s", -25),
+        ];
+        for mode in [OverflowMode::Error, OverflowMode::Wrap] {
+            for (source, expected) in cases {
+                let mut vm = VM::new();
+                vm.set_overflow_mode(mode);
+                let program = Lexer::codegen(source).unwrap();
+                assert_eq!(vm.execute(&program), Ok(Some(expected)), "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn mul_overflow() {
+        let cases = [
+            (i64::MAX, 2, i64::MAX.wrapping_mul(2), i64::MAX),
+            (i64::MIN, -1, i64::MIN, i64::MAX),
+            (i64::MIN, 2, 0, i64::MIN),
+            (
+                -3037000500,
+                3037000500,
+                -3037000500i64.wrapping_mul(3037000500),
+                i64::MIN,
+            ),
+        ];
+        for (a, b, wrapped, saturated) in cases {
+            let program = [Op::Lit(a), Op::Lit(b), Op::Mul];
+
+            let mut vm = VM::new();
+            assert_eq!(vm.execute(&program), Err(VmError::Overflow));
+
+            let mut vm = VM::new();
+            vm.set_overflow_mode(OverflowMode::Wrap);
+            assert_eq!(vm.execute(&program), Ok(Some(wrapped)));
+
+            let mut vm = VM::new();
+            vm.set_overflow_mode(OverflowMode::Saturate);
+            assert_eq!(vm.execute(&program), Ok(Some(saturated)));
+        }
+
+        // Just inside the boundary
+        let mut vm = VM::new();
+        let program = [Op::Lit(3037000499), Op::Lit(3037000499), Op::Mul];
+        assert_eq!(vm.execute(&program), Ok(Some(3037000499 * 3037000499)));
+    }
+
+    #[test]
+    fn add_sub_div_overflow() {
+        let program = [Op::Lit(1), Op::Lit(i64::MAX), Op::Add];
+        assert_eq!(VM::new().execute(&program), Err(VmError::Overflow));
+        let program = [Op::Lit(1), Op::Lit(i64::MIN), Op::Sub];
+        assert_eq!(VM::new().execute(&program), Err(VmError::Overflow));
+        let program = [Op::Lit(-1), Op::Lit(i64::MIN), Op::Div];
+        assert_eq!(VM::new().execute(&program), Err(VmError::Overflow));
+
+        let mut vm = VM::new();
+        vm.set_overflow_mode(OverflowMode::Saturate);
+        assert_eq!(vm.execute(&program), Ok(Some(i64::MAX)));
+    }
+
+    #[test]
+    fn select() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("7 1 10 20 select").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(10)));
+        assert_eq!(vm.stack, [7]);
+
+        let mut vm = VM::new();
+        let program = Lexer::codegen("7 0 10 20 select").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(20)));
+        assert_eq!(vm.stack, [7]);
+    }
+
+    #[test]
+    fn clamp() {
+        let cases = [
+            ("-5 0 10 clamp", 0),
+            ("7 0 10 clamp", 7),
+            ("15 0 10 clamp", 10),
+            ("3 3 3 clamp", 3),
+        ];
+        for (source, expected) in cases {
+            let mut vm = VM::new();
+            let program = Lexer::codegen(source).unwrap();
+            assert_eq!(vm.execute(&program), Ok(Some(expected)), "{}", source);
+        }
+
+        let program = Lexer::codegen("5 10 0 clamp").unwrap();
+        assert_eq!(VM::new().execute(&program), Err(VmError::BadArgument));
+    }
+
+    #[test]
+    fn word_aliases() {
+        let words = Lexer::codegen("1 2 add sub mul div put read eq lt gt").unwrap();
+        let symbols = Lexer::codegen("1 2 + - * / . , = < >").unwrap();
+        assert_eq!(words, symbols);
+        assert_eq!(disassemble(&words), "1 2 + - * / . , = < >");
+    }
+
+    #[test]
+    fn disassemble_round_trip() {
+        let source = "$0 3\n0: 1- dup 0 cjmp\n1: halt";
+        let program = Lexer::codegen(source).unwrap();
+        assert_eq!(disassemble(&program), source);
+    }
+
+    #[test]
+    fn trace_hook() {
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new();
+        let sink = trace.clone();
+        vm.set_trace_hook(move |ip, op, stack| {
+            sink.borrow_mut().push((ip, op.clone(), stack.to_vec()));
+        });
+        let program = Lexer::codegen("2 0 jmp 0: 3 +").unwrap();
+        vm.execute(&program).unwrap();
+
+        assert_eq!(
+            *trace.borrow(),
+            [
+                (0, Op::Lit(2), vec![]),
+                (1, Op::Lit(0), vec![2]),
+                (2, Op::Jmp, vec![2, 0]),
+                (4, Op::Lit(3), vec![2]),
+                (5, Op::Add, vec![2, 3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn coverage() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 0 cjmp 99 . 0: 5 halt 6").unwrap();
+
+        assert_eq!(vm.execute_with_coverage(&program), Ok(vec![3, 4, 8]));
+        assert_eq!(vm.stack, [5]);
+    }
+
+    #[test]
+    fn int_limits() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("maxint minint").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(i64::MIN)));
+        assert_eq!(vm.stack, [i64::MAX]);
+    }
+
+    #[test]
+    fn finish() {
+        let mut vm = VM::new();
+        vm.set_output(SharedBuf::default());
+        vm.record_outputs();
+        let program = Lexer::codegen("3 0: dup . dup 0 store 1- dup 0 cjmp 7").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(7)));
+
+        let outcome = vm.finish();
+        assert_eq!(
+            outcome,
+            VmOutcome {
+                stack: vec![0],
+                memory: vec![1],
+                executed: 2 + 3 * 9 + 1,
+                outputs: vec![3, 2, 1],
+            }
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn replay_inputs() {
+        let cases = [
+            (", , 0 readall", "4 5 hi", Ok(Some(2))),
+            (", , ,", "4 x", Err(VmError::BadInput)),
+        ];
+        for (source, input, expected) in cases {
+            let program = Lexer::codegen(source).unwrap();
+            let mut vm = VM::new();
+            vm.set_input(std::io::Cursor::new(input));
+            vm.record_inputs();
+            assert_eq!(vm.execute(&program), expected, "{}", source);
+            let inputs = vm.take_inputs();
+
+            let mut replayed = VM::new();
+            replayed.set_input(std::io::Cursor::new("1 2 3"));
+            replayed.replay_inputs(inputs);
+            assert_eq!(replayed.execute(&program), expected, "{}", source);
+            assert_eq!(replayed.snapshot(), vm.snapshot(), "{}", source);
+        }
+
+        let mut vm = VM::new();
+        vm.replay_inputs(vec![Input::Int(Some(1)), Input::Bytes(vec![1])]);
+        let program = Lexer::codegen(", , ,").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::Io));
+        assert_eq!(vm.stack, [1, 1]);
+    }
+
+    #[test]
+    fn popcount() {
+        let cases = [
+            (0, 0),
+            (-1, 64),
+            (1, 1),
+            (0b1011, 3),
+            (i64::MIN, 1),
+            (i64::MAX, 63),
+        ];
+        for (a, count) in cases {
+            let mut vm = VM::new();
+            let program = [Op::Lit(a), Op::PopCount];
+            assert_eq!(vm.execute(&program), Ok(Some(count)), "popcount {}", a);
+        }
+    }
+
+    #[test]
+    fn parity() {
+        let cases = [
+            (0, 0),
+            (1, 1),
+            (3, 0),
+            (7, 1),
+            (0b1001_0110, 0),
+            (-1, 0),
+            (i64::MIN, 1),
+        ];
+        for (a, parity) in cases {
+            let mut vm = VM::new();
+            let program = [Op::Lit(a), Op::Parity];
+            assert_eq!(vm.execute(&program), Ok(Some(parity)), "parity {}", a);
+        }
+    }
+
+    #[test]
+    fn leading_trailing_zeros() {
+        let cases = [
+            (0, 64, 64),
+            (1, 63, 0),
+            (64, 57, 6),
+            (-1, 0, 0),
+            (i64::MIN, 0, 63),
+            (12, 60, 2),
+        ];
+        for (a, clz, ctz) in cases {
+            let mut vm = VM::new();
+            let program = [Op::Lit(a), Op::Clz, Op::Lit(a), Op::Ctz];
+            assert_eq!(vm.execute(&program), Ok(Some(ctz)), "ctz {}", a);
+            assert_eq!(vm.stack, [clz], "clz {}", a);
+        }
+    }
+
+    #[test]
+    fn is_pow2() {
+        let cases = [
+            (1, 1),
+            (8, 1),
+            (1 << 62, 1),
+            (0, 0),
+            (-8, 0),
+            (6, 0),
+            (i64::MIN, 0),
+        ];
+        for (a, expected) in cases {
+            let mut vm = VM::new();
+            let program = [Op::Lit(a), Op::IsPow2];
+            assert_eq!(vm.execute(&program), Ok(Some(expected)), "ispow2 {}", a);
+        }
+    }
+
+    #[test]
+    fn digit_value() {
+        let cases = [
+            (b'7' as i64, 7),
+            (b'0' as i64, 0),
+            (b'9' as i64, 9),
+            (b'a' as i64, -1),
+            (-1, -1),
+            (b'7' as i64 + 256, -1),
+        ];
+        for (a, expected) in cases {
+            let mut vm = VM::new();
+            let program = [Op::Lit(a), Op::DigitVal];
+            assert_eq!(vm.execute(&program), Ok(Some(expected)), "digitval {}", a);
+        }
+    }
+
+    #[test]
+    fn dump_string() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("4 0 store 0: 1 2 halt").unwrap();
+        vm.execute(&program).unwrap();
+
+        assert_eq!(vm.dump_string(), "stack [1]\nmemory [4]\njmp table [3]\n");
+    }
+
+    #[test]
+    fn rotate() {
+        let x = 0x0123_4567_89ab_cdef;
+        let cases = [0, 1, 4, 63, 64, 68, -4];
+        for n in cases {
+            let mut vm = VM::new();
+            let program = [
+                Op::Lit(x),
+                Op::Lit(n),
+                Op::RotL,
+                Op::Lit(x),
+                Op::Lit(n),
+                Op::RotR,
+            ];
+            let amount = (n & 63) as u32;
+            assert_eq!(vm.execute(&program), Ok(Some(x.rotate_right(amount))));
+            assert_eq!(vm.stack, [x.rotate_left(amount)]);
+        }
+
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 4 rotl -9223372036854775807 1 rotr").unwrap();
+        assert_eq!(
+            vm.execute(&program),
+            Ok(Some(0xc000_0000_0000_0000_u64 as i64))
+        );
+        assert_eq!(vm.stack, [16]);
+    }
+
+    #[test]
+    fn bytes() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen(
+            "1 0 storeb 2 1 storeb 3 2 storeb 4 3 storeb
+             5 4 storeb 6 5 storeb 7 6 storeb 264 7 storeb
+             255 9 storeb
+             0 load 7 loadb 9 loadb 8 loadb",
+        )
+        .unwrap();
+
+        assert_eq!(vm.execute(&program), Ok(Some(0)));
+        assert_eq!(vm.stack, [0x0807_0605_0403_0201, 8, 255]);
+        assert_eq!(vm.memory, [0x0807_0605_0403_0201, 0xff00]);
+
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 8 storeb").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+    }
+
+    #[test]
+    fn output_limit() {
+        let out = SharedBuf::default();
+        let mut vm = VM::new();
+        vm.set_output(out.clone());
+        vm.set_output_limit(10);
+        let program = Lexer::codegen("0: 42 . cr 0 jmp").unwrap();
+
+        assert_eq!(vm.execute(&program), Err(VmError::OutputLimitExceeded));
+        assert_eq!(out.contents(), "42\n42\n42\n");
+    }
+
+    #[test]
+    fn switch() {
+        let source = "switch[0,1,2] 100 halt 0: 10 halt 1: 11 halt 2: 12";
+        let program = Lexer::codegen(source).unwrap();
+        assert_eq!(program[0], Op::Switch(vec![0, 1, 2]));
+        assert_eq!(disassemble(&program[..1]), "switch[0,1,2]");
+
+        let cases = [(0, 10), (1, 11), (2, 12), (3, 100), (-1, 100)];
+        for (index, expected) in cases {
+            let mut vm = VM::new();
+            vm.stack.push(index);
+            assert_eq!(vm.execute(&program), Ok(Some(expected)), "case {}", index);
+            assert!(vm.stack.is_empty());
+        }
+    }
+
+    #[test]
+    fn tokens() {
+        let span = |line, col| Span { line, col };
+        let tokens = Lexer::tokenize(".data 7\n.text 0: $0 frob # done");
+        assert_eq!(
+            tokens,
+            [
+                Token::Data(span(1, 1)),
+                Token::Word(span(1, 7), "7".to_string()),
+                Token::Text(span(2, 1)),
+                Token::Word(span(2, 7), "0:".to_string()),
+                Token::Word(span(2, 10), "$0".to_string()),
+                Token::Word(span(2, 13), "frob".to_string()),
+                Token::Comment(span(2, 18), " done".to_string()),
+            ]
+        );
+
+        let assembly = Lexer::assemble_tokens(&tokens[..5], LexMode::Strict).unwrap();
+        assert_eq!(assembly.ops, [Op::Label(0), Op::Const(0)]);
+        assert_eq!(assembly.data, [7]);
+        assert_eq!(
+            Lexer::assemble_tokens(&tokens, LexMode::Strict),
+            Err(LexError::UnknownToken(span(2, 13), "frob".to_string()))
+        );
+    }
+
+    #[test]
+    fn token_map() {
+        let mut map = TokenMap::default();
+        map.insert("plus", Op::Add);
+        map.insert(".", Op::PutCr);
+        assert_eq!(
+            Lexer::codegen_with_map("1 2 plus dup . 0:", &map),
+            Ok(vec![
+                Op::Lit(1),
+                Op::Lit(2),
+                Op::Add,
+                Op::Dup,
+                Op::PutCr,
+                Op::Label(0)
+            ])
+        );
+        assert_eq!(
+            Lexer::codegen_with_map("1 2 plus", &TokenMap::default()),
+            Err(LexError::UnknownToken(
+                Span { line: 1, col: 5 },
+                "plus".to_string()
+            ))
+        );
+
+        let mut map = TokenMap::empty();
+        map.insert("plus", Op::Add);
+        assert_eq!(
+            Lexer::codegen_with_map("1 2 plus >r0", &map),
+            Ok(vec![Op::Lit(1), Op::Lit(2), Op::Add, Op::ToReg(0)])
+        );
+        assert_eq!(
+            Lexer::codegen_with_map("1 2 +", &map),
+            Err(LexError::UnknownToken(
+                Span { line: 1, col: 5 },
+                "+".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn lex_modes() {
+        let source = "1 2 frob +";
+        let err = LexError::UnknownToken(Span { line: 1, col: 5 }, "frob".to_string());
+
+        assert_eq!(
+            Lexer::assemble_with_mode(source, LexMode::Strict),
+            Err(err.clone())
+        );
+
+        let assembly = Lexer::assemble_with_mode(source, LexMode::Lenient).unwrap();
+        assert_eq!(assembly.ops, [Op::Lit(1), Op::Lit(2), Op::Add]);
+        assert_eq!(assembly.spans[2], Span { line: 1, col: 10 });
+        assert_eq!(assembly.warnings, [err]);
+    }
+
+    #[test]
+    fn nip() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 2 nip").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(2)));
+        assert!(vm.stack.is_empty());
+
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 nip").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::StackUnderflow));
+    }
+
+    #[test]
+    fn dup_all() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 2 3 dupall").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(3)));
+        assert_eq!(vm.stack, [1, 2, 3, 1, 2]);
+
+        let mut vm = VM::new();
+        let program = Lexer::codegen("dupall").unwrap();
+        assert_eq!(vm.execute(&program), Ok(None));
+    }
+
+    #[test]
+    fn drop_n() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 2 3 4 2 dropn").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(2)));
+        assert_eq!(vm.stack, [1]);
+
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 2 2 dropn 0 dropn").unwrap();
+        assert_eq!(vm.execute(&program), Ok(None));
+
+        for (source, err) in [
+            ("1 2 3 dropn", VmError::StackUnderflow),
+            ("1 -1 dropn", VmError::BadArgument),
+        ] {
+            let program = Lexer::codegen(source).unwrap();
+            assert_eq!(VM::new().execute(&program), Err(err), "{}", source);
+        }
+    }
+
+    #[test]
+    fn reverse_n() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 2 3 4 3 reversen").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(2)));
+        assert_eq!(vm.stack, [1, 4, 3]);
+
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 2 0 reversen").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(2)));
+        assert_eq!(vm.stack, [1]);
+
+        for (source, err) in [
+            ("1 2 3 reversen", VmError::StackUnderflow),
+            ("1 -1 reversen", VmError::BadArgument),
+        ] {
+            let program = Lexer::codegen(source).unwrap();
+            assert_eq!(VM::new().execute(&program), Err(err), "{}", source);
+        }
+    }
+
+    #[test]
+    fn underscores_in_literals() {
+        assert_eq!(Lexer::codegen("1_000"), Ok(vec![Op::Lit(1000)]));
+        assert_eq!(Lexer::codegen("-1_000_000"), Ok(vec![Op::Lit(-1000000)]));
+        for word in ["_5", "5_", "-_5", "_"] {
+            assert_eq!(
+                Lexer::codegen(word),
+                Err(LexError::UnknownToken(
+                    Span { line: 1, col: 1 },
+                    word.to_string()
+                ))
+            );
+        }
+        let assembly = Lexer::assemble(".data 1_0 .text $0").unwrap();
+        assert_eq!(assembly.data, [10]);
+    }
+
+    #[test]
+    fn exit() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("1 2 3 9 exit 4").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(9)));
+        assert_eq!(vm.exit_code(), Some(9));
+        assert_eq!(vm.stack, [1, 2, 3]);
+
+        let program = Lexer::codegen("1 2 halt").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(2)));
+        assert_eq!(vm.exit_code(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn profile() {
+        let mut vm = VM::with_memory_size(1);
+        vm.profile();
+        // Loads the only cell a hundred times
+        let program = Lexer::codegen("100 0: 0 load swap 1- dup 0 cjmp").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(0)));
+        let profile = vm.take_profile();
+        assert_eq!(profile["load"].count, 100);
+        assert_eq!(profile["lit"].count, 201);
+        assert_eq!(profile.values().map(|op| op.count).sum::<u64>(), 702);
+        assert!(vm.take_profile().is_empty());
+
+        let mut vm = VM::with_memory_size(1);
+        assert_eq!(vm.execute(&program), Ok(Some(0)));
+        assert!(vm.take_profile().is_empty());
+    }
+
+    #[test]
+    fn comments_round_trip() {
+        let source = "# sums two values\n1 2 # the values\n+ .\n#done\n";
+        let assembly = Lexer::assemble(source).unwrap();
+        assert_eq!(assembly.ops, Lexer::codegen("1 2 + .").unwrap());
+        assert_eq!(
+            assembly.comments,
+            [
+                (0, " sums two values".to_string()),
+                (2, " the values".to_string()),
+                (4, "done".to_string()),
+            ]
+        );
+
+        let text = disassemble_with_comments(&assembly.ops, &assembly.comments);
+        assert_eq!(text, "# sums two values\n1 2\n# the values\n+ .\n#done");
+        let reassembled = Lexer::assemble(&text).unwrap();
+        assert_eq!(reassembled.ops, assembly.ops);
+        assert_eq!(reassembled.comments, assembly.comments);
+    }
+
+    #[test]
+    fn symbols() {
+        let source = "#@name counter 3\n#@name loop 0:\n0: 3 load 1+ 3 store 0 jmp";
+        let assembly = Lexer::assemble(source).unwrap();
+        assert_eq!(assembly.symbols.addresses[&3], "counter");
+        assert_eq!(assembly.symbols.labels[&0], "loop");
+        assert_eq!(assembly.comments.len(), 2);
+
+        // The names can be used in place of the numbers once they are given
+        let named =
+            "#@name counter 3\n#@name loop 0:\nloop: counter load 1+ counter store loop jmp";
+        assert_eq!(Lexer::assemble(named).unwrap().ops, assembly.ops);
+        assert!(Lexer::assemble("counter #@name counter 3").is_err());
+        // A standard word keeps its meaning
+        let shadowed = Lexer::assemble("#@name dup 3\n1 dup").unwrap();
+        assert_eq!(shadowed.ops, [Op::Lit(1), Op::Dup]);
+
+        for bad in [
+            "#@name 3 4",
+            "#@name x",
+            "#@name x -1",
+            "#@name x y:",
+            "#@name x 1 2",
+        ] {
+            assert!(Lexer::assemble(bad).is_err(), "{}", bad);
+            let assembly = Lexer::assemble_with_mode(bad, LexMode::Lenient).unwrap();
+            assert_eq!(assembly.warnings.len(), 1, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn symbols_round_trip() {
+        let source = "#@name loop 0:\n#@name done 1:\n#@name n 0\n\
+                      0: 0 load 1- dup 0 store 1 jz 0 jmp 1: halt";
+        let assembly = Lexer::assemble(source).unwrap();
+
+        let text = disassemble_with_symbols(&assembly.ops, &assembly.symbols);
+        assert_eq!(
+            text,
+            "#@name n 0\n#@name loop 0:\n#@name done 1:\n\
+             loop: n load 1- dup n store done jz loop jmp\ndone: halt"
+        );
+        let reassembled = Lexer::assemble(&text).unwrap();
+        assert_eq!(reassembled.ops, assembly.ops);
+        assert_eq!(reassembled.symbols, assembly.symbols);
+    }
+
+    #[test]
+    fn syscall() {
+        let mut vm = VM::new();
+        vm.register_syscall(3, |stack| {
+            let a = stack.pop().ok_or(VmError::StackUnderflow)?;
+            stack.push(a.checked_mul(2).ok_or(VmError::Overflow)?);
+            Ok(())
+        });
+        let program = Lexer::codegen("1 21 syscall[3]").unwrap();
+        assert_eq!(program[2], Op::Syscall(3));
+        assert_eq!(disassemble(&program), "1 21 syscall[3]");
+        assert_eq!(vm.execute(&program), Ok(Some(42)));
+        assert_eq!(vm.stack, [1]);
+
+        let program = Lexer::codegen("maxint syscall[3]").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::Overflow));
+        let program = Lexer::codegen("1 syscall[0]").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::UnknownSyscall));
+    }
+
+    #[test]
+    fn registers() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("7 >r0 9 >r15 1 2 + dup * r0> r15> r0>").unwrap();
+        assert_eq!(program[1], Op::ToReg(0));
+        assert_eq!(program[10], Op::FromReg(15));
+        assert_eq!(disassemble(&program[1..4]), ">r0 9 >r15");
+        assert_eq!(vm.execute(&program), Ok(Some(7)));
+        assert_eq!(vm.stack, [9, 7, 9]);
+
+        assert!(Lexer::codegen(">r16").is_err());
+        assert!(Lexer::codegen("r>").is_err());
+        assert_eq!(
+            VM::new().execute(&[Op::FromReg(16)]),
+            Err(VmError::BadArgument)
+        );
+        assert_eq!(
+            VM::new().execute(&[Op::ToReg(0)]),
+            Err(VmError::StackUnderflow)
+        );
+    }
+
+    #[test]
+    fn memory_mapped_io() {
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new();
+        let log = written.clone();
+        vm.map_memory(
+            100..102,
+            |ptr| Ok(ptr as i64 * 2),
+            move |ptr, a| {
+                log.borrow_mut().push((ptr, a));
+                Ok(())
+            },
+        );
+        vm.map_memory(
+            101..102,
+            |_| Err(VmError::BadInput),
+            |_, _| Err(VmError::Io),
+        );
+
+        let program = Lexer::codegen("7 100 store 1 0 store 100 load").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(200)));
+        assert_eq!(*written.borrow(), [(100, 7)]);
+        assert_eq!(vm.memory, [1]);
+
+        let program = Lexer::codegen("101 load").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadInput));
+        let program = Lexer::codegen("7 101 store").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::Io));
+        let program = Lexer::codegen("102 load").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::BadMemoryAccess));
+    }
+
+    #[test]
+    fn run_sequence() {
+        let setup = Lexer::codegen("0 trap 5 0 store 7 1 store 9 0: 0").unwrap();
+        let compute = Lexer::codegen("0 load 1 load + 0 1 /").unwrap();
+        let mut vm = VM::new();
+        // The trap handler of the first program does not catch the division
+        assert_eq!(
+            vm.run_sequence(&[&setup, &compute]),
+            Err(VmError::DivideByZero)
+        );
+
+        let compute = Lexer::codegen("0 load 1 load +").unwrap();
+        let mut vm = VM::new();
+        assert_eq!(vm.run_sequence(&[&setup, &compute]), Ok(Some(12)));
+        assert!(vm.stack.is_empty());
+        assert_eq!(vm.memory, [5, 7]);
+
+        let exit = Lexer::codegen("3 exit").unwrap();
+        let mut vm = VM::new();
+        assert_eq!(vm.run_sequence(&[&setup, &exit, &compute]), Ok(Some(3)));
+        assert_eq!(vm.run_sequence(&[]), Ok(None));
+    }
+
+    #[test]
+    fn args() {
+        let mut vm = VM::new();
+        vm.set_args(vec!["10".to_string(), "20".to_string(), "x".to_string()]);
+        let program = Lexer::codegen("0 arg 1 arg").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(20)));
+        assert_eq!(vm.stack, [10]);
+
+        for (source, err) in [
+            ("2 arg", VmError::BadInput),
+            ("3 arg", VmError::BadArgument),
+            ("-1 arg", VmError::BadArgument),
+        ] {
+            let program = Lexer::codegen(source).unwrap();
+            assert_eq!(vm.execute(&program), Err(err), "{}", source);
+        }
+    }
+
+    #[test]
+    fn jump_lands_after_label() {
+        let visited = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new();
+        let trace = visited.clone();
+        vm.set_trace_hook(move |ip, _, _| trace.borrow_mut().push(ip));
+        // Label 1 is defined before label 0
+        let program = Lexer::codegen("0 jmp 1: 7 halt 0: here 1 jmp").unwrap();
+        assert_eq!(vm.execute(&program), Ok(Some(7)));
+        assert_eq!(vm.stack, [6]);
+        assert_eq!(*visited.borrow(), [0, 1, 6, 7, 8, 3, 4]);
+    }
+
+    #[test]
+    fn duplicate_label() {
+        let mut vm = VM::new();
+        let program = Lexer::codegen("0: 1 1: 2 0: 3").unwrap();
+        assert_eq!(vm.execute(&program), Err(VmError::DuplicateLabel));
+        assert_eq!(vm.fault().unwrap().at, 4);
+        assert!(!VmError::DuplicateLabel.is_catchable());
+    }
+
+    // The fused ops stand for the most common pairs of ops in the example
+    // programs and tests: `1- dup` ends almost every countdown loop and
+    // `. cr` writes a line
+    #[test]
+    fn fused_ops() {
+        let cases = [
+            ("5 1-dup", "5 1- dup"),
+            ("minint 1-dup", "minint 1- dup"),
+            ("1-dup", "1- dup"),
+            ("1 2 .cr .cr", "1 2 . cr . cr"),
+            (".cr", ". cr"),
+        ];
+        for (fused, expanded) in cases {
+            let mut results = Vec::new();
+            for source in [fused, expanded] {
+                let out = SharedBuf::default();
+                let mut vm = VM::new();
+                vm.set_output(out.clone());
+                let res = vm.execute(&Lexer::codegen(source).unwrap());
+                results.push((res, vm.stack, out.contents()));
+            }
+            assert_eq!(results[0], results[1], "{}", fused);
+        }
+    }
+
+    #[test]
+    fn put_str_mem() {
+        let out = SharedBuf::default();
+        let mut vm = VM::new();
+        vm.set_output(out.clone());
+        let program = Lexer::codegen("72 0 store 105 1 store 0 2 store 0 putstr 1 putstr").unwrap();
+        assert_eq!(vm.execute(&program), Ok(None));
+        assert_eq!(out.contents(), "Hii");
+
+        let cases = [
+            ("72 0 store 0 putstr", Err(VmError::BadMemoryAccess)),
+            ("-1 0 store 0 1 store 0 putstr", Err(VmError::BadArgument)),
+            (
+                "55296 0 store 0 1 store 0 putstr",
+                Err(VmError::BadArgument),
+            ),
+            ("-1 putstr", Err(VmError::BadMemoryAccess)),
+        ];
+        for (source, expected) in cases {
+            let program = Lexer::codegen(source).unwrap();
+            assert_eq!(VM::new().execute(&program), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn to_digits() {
+        let cases = [
+            ("0 12345 todigits", Ok(Some(5)), vec![1, 2, 3, 4, 5]),
+            ("0 0 todigits", Ok(Some(1)), vec![0]),
+            ("0 -42 todigits", Ok(Some(-2)), vec![4, 2]),
+            ("7 0 store 1 9 todigits", Ok(Some(1)), vec![7, 9]),
+            (
+                "0 minint todigits",
+                Ok(Some(-19)),
+                vec![9, 2, 2, 3, 3, 7, 2, 0, 3, 6, 8, 5, 4, 7, 7, 5, 8, 0, 8],
+            ),
+            ("1 5 todigits", Err(VmError::BadMemoryAccess), vec![]),
+            ("-1 5 todigits", Err(VmError::BadMemoryAccess), vec![]),
+        ];
+        for (source, expected, memory) in cases {
+            let mut vm = VM::new();
+            let program = Lexer::codegen(source).unwrap();
+            assert_eq!(vm.execute(&program), expected, "{}", source);
+            assert_eq!(vm.memory, memory, "{}", source);
+        }
+    }
+
+    #[test]
+    fn mem_eq() {
+        let setup = "1 0 store 2 1 store 1 2 store 2 3 store 5 4 store";
+        let cases = [
+            ("0 2 2 memeq", Ok(Some(1))),
+            ("0 2 3 memeq", Ok(Some(0))),
+            ("4 4 1 memeq", Ok(Some(1))),
+            ("0 4 1 memeq", Ok(Some(0))),
+            ("0 0 5 memeq", Ok(Some(1))),
+            ("3 4 0 memeq", Ok(Some(1))),
+            ("0 4 2 memeq", Err(VmError::BadMemoryAccess)),
+            ("-1 0 1 memeq", Err(VmError::BadMemoryAccess)),
+            ("0 1 -1 memeq", Err(VmError::BadMemoryAccess)),
+        ];
+        for (source, expected) in cases {
+            let program = Lexer::codegen(&format!("{} {}", setup, source)).unwrap();
+            assert_eq!(VM::new().execute(&program), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn mem_max_min() {
+        let setup = "3 0 store -7 1 store 12 2 store 0 3 store";
+        let cases = [
+            ("0 4 memmax", Ok(Some(12))),
+            ("0 4 memmin", Ok(Some(-7))),
+            ("0 2 memmax", Ok(Some(3))),
+            ("2 2 memmin", Ok(Some(0))),
+            ("1 1 memmax", Ok(Some(-7))),
+            ("1 0 memmax", Err(VmError::BadArgument)),
+            ("4 0 memmin", Err(VmError::BadArgument)),
+            ("2 3 memmax", Err(VmError::BadMemoryAccess)),
+            ("-1 2 memmin", Err(VmError::BadMemoryAccess)),
+        ];
+        for (source, expected) in cases {
+            let program = Lexer::codegen(&format!("{} {}", setup, source)).unwrap();
+            assert_eq!(VM::new().execute(&program), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn cas() {
+        let cases = [
+            ("5 0 store 0 5 9 cas", Ok(Some(1)), vec![9]),
+            ("5 0 store 0 4 9 cas", Ok(Some(0)), vec![5]),
+            ("5 0 store 0 5 9 cas 0 5 7 cas", Ok(Some(0)), vec![9]),
+            (
+                "5 0 store 1 5 9 cas",
+                Err(VmError::BadMemoryAccess),
+                vec![5],
+            ),
+            (
+                "5 0 store -1 5 9 cas",
+                Err(VmError::BadMemoryAccess),
+                vec![5],
+            ),
+        ];
+        for (source, expected, memory) in cases {
+            let mut vm = VM::new();
+            let program = Lexer::codegen(source).unwrap();
+            assert_eq!(vm.execute(&program), expected, "{}", source);
+            assert_eq!(vm.memory, memory, "{}", source);
+        }
+    }
+}
@@ -1,227 +1,112 @@
-pub enum Op {
-    Add,          // pop two values, add them, and push the result
-    Sub,          // pop two values, subtract them, and push the result
-    Mul,          // pop two values, multiply them, and push the result
-    Div,          // pop two values, divide them, and push the result
-    Lit(i64),     // push a literal onto the stack
-    Load,         // load a value from memory and push it onto the stack
-    Store,        // pop a value and store it in memory
-    Label(usize), // create a label to jump to later
-    Jmp,          // unconditional jump to a label
-    CJmp,         // pop a value off the stack and jump if the value is non-zero
-    Put,          // pop a value off the stack and write it to stdout
-    CR,           // write newline to stdout
-    Dup,          // duplicate the top value onto the stack
-    Swap,         // swap the top two values on the stack
-    Eq,           // pop two values and put a one onto the stack if a == b, otherwise put zero
-    Lt,           // pop two values and put a one onto the stack if a < b, otherwise put zero
-    Gt,           // pop two values and put a one onto the stack if a > b, otherwise put zero
-    Dump,         // debug core dump
-    Halt,         // debug stop
-}
-
-pub struct VM {
-    stack: Vec<i64>,
-    memory: Vec<i64>,
-    jump_table: Vec<usize>,
-}
+use bytti::{LexError, Lexer, Span, VM};
+use std::cell::RefCell;
+use std::io::{BufWriter, Write};
+use std::rc::Rc;
 
-impl VM {
-    pub const fn new() -> VM {
-        VM {
-            stack: Vec::new(),
-            memory: Vec::new(),
-            jump_table: Vec::new(),
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut input_path = None;
+    let mut trace_path = None;
+    let mut json_errors = false;
+    let mut program_args = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trace" if input_path.is_none() => {
+                trace_path = Some(args.next().expect("--trace needs a file"))
+            }
+            "--error-format=json" if input_path.is_none() => json_errors = true,
+            "--error-format=human" if input_path.is_none() => json_errors = false,
+            // Everything after the program is for the program
+            _ if input_path.is_some() => program_args.push(arg),
+            _ => input_path = Some(arg),
         }
     }
 
-    pub fn dump(&self) {
-        println!("stack {:?}", self.stack);
-        println!("memory {:?}", self.memory);
-        println!("jmp table {:?}", self.jump_table);
-    }
+    // Read the program from stdin when no path or `-` is given
+    let input = match input_path {
+        Some(path) if path != "-" => std::fs::read_to_string(path).unwrap(),
+        _ => std::io::read_to_string(std::io::stdin()).unwrap(),
+    };
 
-    pub fn excecute(&mut self, program: &Vec<Op>) -> Option<i64> {
-        // Populate jump table
-        for (i, op) in program.iter().enumerate() {
-            match op {
-                Op::Label(label) => {
-                    assert!(*label <= self.jump_table.len());
-                    if self.jump_table.len() <= *label {
-                        self.jump_table.push(i);
-                    } else {
-                        self.jump_table[*label] = i;
-                    }
-                }
-                _ => (),
-            }
-        }
-
-        let mut i = 0; // Instruction pointer
-        loop {
-            if i >= program.len() {
-                break;
-            }
-            let op = &program[i];
-            match op {
-                Op::Add => {
-                    let a = self.stack.pop()?;
-                    let b = self.stack.pop()?;
-                    self.stack.push(a + b);
-                }
-                Op::Sub => {
-                    let a = self.stack.pop()?;
-                    let b = self.stack.pop()?;
-                    self.stack.push(a - b);
-                }
-                Op::Mul => {
-                    let a = self.stack.pop()?;
-                    let b = self.stack.pop()?;
-                    self.stack.push(a * b);
-                }
-                Op::Div => {
-                    let a = self.stack.pop()?;
-                    let b = self.stack.pop()?;
-                    self.stack.push(a / b); // TODO: this can fail
-                }
-                Op::Lit(x) => self.stack.push(*x),
-                Op::Load => {
-                    let ptr = self.stack.pop()? as usize; // TODO: deal with negatives
-                    let a = self.memory[ptr];
-                    self.stack.push(a);
-                }
-                Op::Store => {
-                    let ptr = self.stack.pop()? as usize;
-                    let a = self.stack.pop()?;
-                    assert!(ptr <= self.memory.len());
-                    if self.memory.len() <= ptr {
-                        self.memory.push(a);
-                    } else {
-                        self.memory[ptr] = a;
-                    }
-                }
-                Op::Label(_) => (),
-                Op::Jmp => {
-                    let label = self.stack.pop()? as usize;
-                    i = self.jump_table[label];
-                }
-                Op::CJmp => {
-                    let label = self.stack.pop()? as usize;
-                    let a = self.stack.pop()?;
-                    if a != 0 {
-                        i = self.jump_table[label];
-                    }
-                }
-                Op::Put => print!("{}", self.stack.pop()?),
-                Op::CR => println!(""),
-                Op::Dup => {
-                    let a = self.stack.pop()?;
-                    self.stack.push(a);
-                    self.stack.push(a);
-                }
-                Op::Swap => {
-                    let a = self.stack.pop()?;
-                    let b = self.stack.pop()?;
-                    self.stack.push(a);
-                    self.stack.push(b);
-                }
-                Op::Eq => {
-                    let a = self.stack.pop();
-                    let b = self.stack.pop();
-                    self.stack.push(if a == b { 1 } else { 0 });
-                }
-                Op::Lt => {
-                    let a = self.stack.pop();
-                    let b = self.stack.pop();
-                    self.stack.push(if a < b { 1 } else { 0 });
+    let assembly = match Lexer::assemble(&input) {
+        Ok(assembly) => assembly,
+        Err(err) if json_errors => {
+            let (kind, span, message) = match &err {
+                LexError::UnknownToken(span, token) => {
+                    ("UnknownToken", span, format!("unknown token `{}`", token))
                 }
-                Op::Gt => {
-                    let a = self.stack.pop();
-                    let b = self.stack.pop();
-                    self.stack.push(if a < b { 1 } else { 0 });
+                LexError::BadLabel(span, token) => {
+                    ("BadLabel", span, format!("bad label `{}`", token))
                 }
-                Op::Dump => {
-                    self.dump();
-                }
-                Op::Halt => {
-                    break;
-                }
-            }
-            i += 1;
-            // std::thread::sleep(std::time::Duration::from_nanos(100));
+            };
+            eprintln!("{}", json_error(kind, &message, Some(span)));
+            std::process::exit(1);
         }
-        self.stack.pop()
-    }
-}
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut vm = VM::new();
+    vm.set_data(assembly.data);
+    vm.set_args(program_args);
 
-struct Lexer {}
+    // The trace is shared with the hook so it can be flushed however the
+    // program ends
+    let trace = trace_path.map(|path| {
+        let file = std::fs::File::create(path).unwrap();
+        Rc::new(RefCell::new(BufWriter::new(file)))
+    });
+    if let Some(trace) = &trace {
+        let trace = trace.clone();
+        vm.set_trace_hook(move |ip, op, stack| {
+            let _ = writeln!(trace.borrow_mut(), "{} {} {:?}", ip, op, stack);
+        });
+    }
 
-impl Lexer {
-    fn codegen(program: &str) -> Vec<Op> {
-        let mut tokens = Vec::new();
-        for x in program.split_whitespace() {
-            let op = match x {
-                "+" => Op::Add,
-                "-" => Op::Sub,
-                "*" => Op::Mul,
-                "/" => Op::Div,
-                "load" => Op::Load,
-                "store" => Op::Store,
-                "jmp" => Op::Jmp,
-                "cjmp" => Op::CJmp,
-                "." => Op::Put,
-                "cr" => Op::CR,
-                "dup" => Op::Dup,
-                "swap" => Op::Swap,
-                "=" => Op::Eq,
-                "<" => Op::Lt,
-                ">" => Op::Gt,
-                "?" => Op::Dump,
-                "halt" => Op::Halt,
-                lit => {
-                    let parse_res = lit.parse::<i64>();
-                    if parse_res.is_ok() {
-                        Op::Lit(parse_res.unwrap())
-                    } else {
-                        let mut label = lit.chars();
-                        if label.next_back() != Some(':') {
-                            println!("{lit}");
-                            assert!(false);
-                        }
-                        let label = label.as_str();
-                        Op::Label(label.parse().unwrap())
-                    }
-                }
-            };
-            tokens.push(op);
+    let res = vm.execute(&assembly.ops);
+    if let Some(trace) = &trace {
+        trace.borrow_mut().flush().unwrap();
+    }
+    if let Err(err) = res {
+        if json_errors {
+            let span = vm.fault().and_then(|fault| assembly.spans.get(fault.at));
+            let kind = format!("{:?}", err);
+            eprintln!("{}", json_error(&kind, &err.to_string(), span));
+        } else {
+            eprintln!("{}", vm.format_error(&err, &assembly.spans));
         }
-        tokens
+        std::process::exit(1);
+    }
+    if let Some(code) = vm.exit_code() {
+        // A code the process cannot exit with still has to report failure
+        std::process::exit(i32::try_from(code).unwrap_or(1));
     }
 }
 
-fn main() {
-    let mut args = std::env::args();
-    let program = args.next().unwrap();
-    let input_path = args.next().unwrap();
-    let input = std::fs::read_to_string(input_path).unwrap();
-
-    let bytecode = Lexer::codegen(&input);
-    let mut vm = VM::new();
-    let _exit_code = vm.excecute(&bytecode);
+// An error for `--error-format=json`, one object on a single line
+fn json_error(kind: &str, message: &str, span: Option<&Span>) -> String {
+    let mut json = format!(
+        "{{\"kind\":{},\"message\":{}",
+        json_string(kind),
+        json_string(message)
+    );
+    if let Some(span) = span {
+        json += &format!(",\"line\":{},\"col\":{}", span.line, span.col);
+    }
+    json + "}"
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    // TODO: prop tests
-
-    #[test]
-    fn addition() {
-        let mut vm = VM::new();
-        let program = vec![Op::Lit(1), Op::Lit(2), Op::Add];
-
-        let top = vm.excecute(&program).unwrap();
-        assert_eq!(top, 3)
+fn json_string(s: &str) -> String {
+    let mut json = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json += &format!("\\u{:04x}", c as u32),
+            c => json.push(c),
+        }
     }
+    json.push('"');
+    json
 }
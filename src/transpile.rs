@@ -0,0 +1,199 @@
+//! Translation of bytecode to Rust source, enabled by the `transpile` feature.
+//!
+//! The generated function keeps the VM's stack and memory in native vectors
+//! and dispatches on the instruction pointer, so programs with any control
+//! flow translate directly. It supports the arithmetic ops (`Add`, `Sub`,
+//! `Mul`, `Div`, `Inc`, `Dec`), literals and `MaxInt`/`MinInt`, the stack ops
+//! (`Dup`, `Swap`, `Tuck`, `Nip`), comparisons and `Select`, `Load` and
+//! `Store`, `Put` and `CR`, and the control flow ops (`Label`, `Start`, `Jmp`,
+//! `CJmp`, the fused compare-and-jumps, `Halt`). Any other op makes the
+//! generated function return an error when it is reached.
+//!
+//! Errors and overflow behave like a VM with the default settings.
+
+use crate::{check_labels, labels, Op, VmError};
+use std::fmt::Write;
+
+/// Generates a self-contained Rust function
+/// `pub fn run() -> Result<Option<i64>, &'static str>` that behaves like
+/// `VM::execute` on `program`. Errors are reported with the message of the
+/// corresponding `VmError`.
+///
+/// A program `VM::prepare` rejects, because a label is defined twice or the
+/// label ids leave a gap, is rejected here with the same error.
+pub fn transpile(program: &[Op]) -> Result<String, VmError> {
+    check_labels(program).map_err(|(error, _)| error)?;
+    let mut out = String::new();
+    let start = program.iter().position(|op| *op == Op::Start).unwrap_or(0);
+
+    out.push_str("#[allow(unused_mut, unused_variables, dead_code, unreachable_code)]\n");
+    out.push_str("pub fn run() -> Result<Option<i64>, &'static str> {\n");
+    out.push_str("    const UNDERFLOW: &str = \"stack underflow\";\n");
+    out.push_str("    const OVERFLOW: &str = \"arithmetic overflow\";\n");
+    out.push_str("    const BAD_MEMORY: &str = \"bad memory access\";\n");
+    out.push_str("    fn target(label: i64) -> Result<usize, &'static str> {\n");
+    out.push_str("        match label {\n");
+    for (label, i) in labels(program) {
+        writeln!(out, "            {} => Ok({}),", label, i).unwrap();
+    }
+    out.push_str("            _ => Err(\"jump to undefined label\"),\n");
+    out.push_str("        }\n");
+    out.push_str("    }\n");
+    out.push_str("    let mut stack: Vec<i64> = Vec::new();\n");
+    out.push_str("    let mut memory: Vec<i64> = Vec::new();\n");
+    writeln!(out, "    let mut pc: usize = {};", start).unwrap();
+    writeln!(out, "    while pc < {} {{", program.len()).unwrap();
+    out.push_str("        match pc {\n");
+    for (i, op) in program.iter().enumerate() {
+        writeln!(out, "            {} => {{", i).unwrap();
+        for line in op_body(op).lines() {
+            writeln!(out, "                {}", line).unwrap();
+        }
+        out.push_str("            }\n");
+    }
+    out.push_str("            _ => unreachable!(),\n");
+    out.push_str("        }\n");
+    out.push_str("        pc += 1;\n");
+    out.push_str("    }\n");
+    out.push_str("    Ok(stack.pop())\n");
+    out.push_str("}\n");
+    Ok(out)
+}
+
+// The statements implementing a single op
+fn op_body(op: &Op) -> String {
+    const A: &str = "let a = stack.pop().ok_or(UNDERFLOW)?;\n";
+    const AB: &str = "let a = stack.pop().ok_or(UNDERFLOW)?;\n\
+                      let b = stack.pop().ok_or(UNDERFLOW)?;\n";
+    const LABEL: &str = "let label = stack.pop().ok_or(UNDERFLOW)?;\n";
+    match op {
+        Op::Add => format!("{}stack.push(a.checked_add(b).ok_or(OVERFLOW)?);", AB),
+        Op::Sub => format!("{}stack.push(a.checked_sub(b).ok_or(OVERFLOW)?);", AB),
+        Op::Mul => format!("{}stack.push(a.checked_mul(b).ok_or(OVERFLOW)?);", AB),
+        Op::Div => format!(
+            "{}if b == 0 {{\n    return Err(\"divide by zero\");\n}}\n\
+             stack.push(a.checked_div(b).ok_or(OVERFLOW)?);",
+            AB
+        ),
+        Op::Inc => format!("{}stack.push(a.checked_add(1).ok_or(OVERFLOW)?);", A),
+        Op::Dec => format!("{}stack.push(a.checked_sub(1).ok_or(OVERFLOW)?);", A),
+        Op::Lit(x) => format!("stack.push({}_i64);", x),
+        Op::MaxInt => "stack.push(i64::MAX);".to_string(),
+        Op::MinInt => "stack.push(i64::MIN);".to_string(),
+        Op::Dup => format!("{}stack.push(a);\nstack.push(a);", A),
+        Op::Swap => format!("{}stack.push(a);\nstack.push(b);", AB),
+        Op::Tuck => format!("{}stack.push(a);\nstack.push(b);\nstack.push(a);", AB),
+        Op::Nip => format!("{}stack.push(a);", AB),
+        Op::Eq => format!("{}stack.push((a == b) as i64);", AB),
+        Op::Lt => format!("{}stack.push((a < b) as i64);", AB),
+        Op::Gt => format!("{}stack.push((a > b) as i64);", AB),
+        Op::Select => "let b = stack.pop().ok_or(UNDERFLOW)?;\n\
+                       let a = stack.pop().ok_or(UNDERFLOW)?;\n\
+                       let cond = stack.pop().ok_or(UNDERFLOW)?;\n\
+                       stack.push(if cond != 0 { a } else { b });"
+            .to_string(),
+        Op::Load => format!(
+            "{}let ptr = usize::try_from(a).map_err(|_| BAD_MEMORY)?;\n\
+             stack.push(*memory.get(ptr).ok_or(BAD_MEMORY)?);",
+            A
+        ),
+        Op::Store => format!(
+            "{}let ptr = usize::try_from(a).map_err(|_| BAD_MEMORY)?;\n\
+             if ptr > memory.len() {{\n    return Err(BAD_MEMORY);\n}}\n\
+             if ptr == memory.len() {{\n    memory.push(b);\n}} else {{\n    memory[ptr] = b;\n}}",
+            AB
+        ),
+        Op::Put => format!("{}print!(\"{{}}\", a);", A),
+        Op::CR => "println!();".to_string(),
+        Op::Label(_) | Op::Start => String::new(),
+        Op::Jmp => format!("{}pc = target(label)?;", LABEL),
+        Op::CJmp | Op::JmpNeg | Op::JmpPos | Op::JmpZero => {
+            let cond = match op {
+                Op::CJmp => "a != 0",
+                Op::JmpNeg => "a < 0",
+                Op::JmpPos => "a > 0",
+                _ => "a == 0",
+            };
+            format!("{}{}if {} {{\n    pc = target(label)?;\n}}", LABEL, A, cond)
+        }
+        Op::JmpEq | Op::JmpNe | Op::JmpLt | Op::JmpGt => {
+            let cmp = match op {
+                Op::JmpEq => "==",
+                Op::JmpNe => "!=",
+                Op::JmpLt => "<",
+                _ => ">",
+            };
+            format!(
+                "{}{}if a {} b {{\n    pc = target(label)?;\n}}",
+                LABEL, AB, cmp
+            )
+        }
+        Op::Halt => "break;".to_string(),
+        op => format!("return Err(\"unsupported op: {}\");", op.name()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lexer, VM};
+    use std::process::Command;
+
+    // Compiles the transpiled program with a `main` that prints the result of
+    // `run` after the program's own output, and returns what it printed
+    fn build_and_run(program: &[Op]) -> String {
+        let dir = std::env::temp_dir().join(format!("bytti-transpile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("main.rs");
+        let binary = dir.join("main");
+        let main = "fn main() {\n    let res = run();\n    print!(\"\\n{:?}\", res);\n}\n";
+        std::fs::write(&source, transpile(program).unwrap() + main).unwrap();
+
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+        let status = Command::new(rustc)
+            .args(["--edition", "2021", "-o"])
+            .arg(&binary)
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let output = Command::new(&binary).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn transpiled_program_matches_vm() {
+        // Sums 1 to 10 with a loop, prints the sum and then fails on a
+        // division by zero
+        let source = "0 10 0: tuck + swap 1- dup 0 cjmp + dup . cr 0 1 1 jne 1: 0 swap /";
+        let program = Lexer::codegen(source).unwrap();
+
+        let mut vm = VM::new();
+        vm.set_output(std::io::sink());
+        let expected = vm.execute(&program).map_err(|err| err.to_string());
+        assert_eq!(expected, Err("divide by zero".to_string()));
+        assert_eq!(build_and_run(&program), format!("55\n\n{:?}", expected));
+    }
+
+    #[test]
+    fn unsupported_ops() {
+        let program = Lexer::codegen("4 sqrt").unwrap();
+        assert!(transpile(&program)
+            .unwrap()
+            .contains("return Err(\"unsupported op: sqrt\");"));
+    }
+
+    #[test]
+    fn bad_labels() {
+        let cases = [
+            ("0: 1 0: 2", VmError::DuplicateLabel),
+            ("0: 1 2: 2", VmError::BadLabel),
+        ];
+        for (source, expected) in cases {
+            let program = Lexer::codegen(source).unwrap();
+            assert_eq!(VM::new().execute(&program), Err(expected), "{}", source);
+            assert_eq!(transpile(&program), Err(expected), "{}", source);
+        }
+    }
+}
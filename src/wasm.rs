@@ -0,0 +1,316 @@
+//! Translation of bytecode to a WebAssembly text module, enabled by the
+//! `wasm` feature, to run programs in a browser.
+//!
+//! The module imports `env.put`, called with each value `Put` writes, and
+//! `env.cr`, called for each `CR`, and exports its linear memory and a
+//! `run` function that runs the program and returns the top of the stack,
+//! or zero when the stack is empty. The first half of the single memory page
+//! holds the stack, 4096 values, and the second half the VM's memory, 4096
+//! cells, which grows by one cell at a time on stores like in the VM.
+//!
+//! It supports literals and `MaxInt`/`MinInt`, the arithmetic ops (`Add`,
+//! `Sub`, `Mul`, `Div`, `Inc`, `Dec`), the stack ops (`Dup`, `Swap`, `Tuck`,
+//! `Nip`), comparisons and `Select`, `Load` and `Store`, `Put` and `CR`, and
+//! the control flow ops (`Label`, `Start`, `Jmp`, `CJmp`, the fused
+//! compare-and-jumps, `JmpNeg`, `JmpPos`, `JmpZero`, `Halt`). Any other op
+//! traps when it is reached.
+//!
+//! Arithmetic wraps, like a VM with `OverflowMode::Wrap`, so `MinInt`
+//! divided by -1 is `MinInt` rather than the trap `i64.div_s` would raise.
+//! Every error, including dividing by zero and overflowing the stack or
+//! memory, is a trap.
+
+use crate::{labels, Op};
+use alloc::format;
+use alloc::string::String;
+use core::fmt::Write;
+
+// Bytes of the memory page used for the stack, the VM's memory comes after
+const STACK_BYTES: usize = 32768;
+const MEMORY_CELLS: usize = 4096;
+
+/// Generates a WebAssembly text module that runs `program`, see the module
+/// docs.
+pub fn to_wat(program: &[Op]) -> String {
+    let mut out = String::new();
+    let start = program.iter().position(|op| *op == Op::Start).unwrap_or(0);
+
+    out.push_str("(module\n");
+    out.push_str("  (import \"env\" \"put\" (func $put (param i64)))\n");
+    out.push_str("  (import \"env\" \"cr\" (func $cr))\n");
+    out.push_str("  (memory (export \"memory\") 1)\n");
+    out.push_str("  (global $sp (mut i32) (i32.const 0))\n");
+    out.push_str("  (global $len (mut i32) (i32.const 0))\n");
+    out.push_str("  (func $push (param $x i64)\n");
+    writeln!(
+        out,
+        "    (if (i32.ge_u (global.get $sp) (i32.const {})) (then unreachable))",
+        STACK_BYTES
+    )
+    .unwrap();
+    out.push_str("    (i64.store (global.get $sp) (local.get $x))\n");
+    out.push_str("    (global.set $sp (i32.add (global.get $sp) (i32.const 8))))\n");
+    out.push_str("  (func $pop (result i64)\n");
+    out.push_str("    (if (i32.eqz (global.get $sp)) (then unreachable))\n");
+    out.push_str("    (global.set $sp (i32.sub (global.get $sp) (i32.const 8)))\n");
+    out.push_str("    (i64.load (global.get $sp)))\n");
+    out.push_str("  (func $addr (param $ptr i64) (param $end i32) (result i32)\n");
+    out.push_str(
+        "    (if (i64.ge_u (local.get $ptr) (i64.extend_i32_u (local.get $end))) (then unreachable))\n",
+    );
+    writeln!(
+        out,
+        "    (i32.add (i32.const {}) (i32.shl (i32.wrap_i64 (local.get $ptr)) (i32.const 3))))",
+        STACK_BYTES
+    )
+    .unwrap();
+    out.push_str("  (func $target (param $label i64) (result i32)\n");
+    for (label, i) in labels(program) {
+        writeln!(
+            out,
+            "    (if (i64.eq (local.get $label) (i64.const {})) (then (return (i32.const {}))))",
+            label, i
+        )
+        .unwrap();
+    }
+    out.push_str("    unreachable)\n");
+
+    out.push_str("  (func (export \"run\") (result i64)\n");
+    out.push_str("    (local $pc i32) (local $a i64) (local $b i64) (local $c i64)\n");
+    writeln!(out, "    (local.set $pc (i32.const {}))", start).unwrap();
+    // Instruction i runs after block $i{i} ends, so branching out of that
+    // block jumps to it, and every instruction falls through to the next
+    out.push_str("    (loop $dispatch\n");
+    out.push_str("      (block $end\n");
+    for i in (0..program.len()).rev() {
+        writeln!(out, "      (block $i{}", i).unwrap();
+    }
+    out.push_str("        (br_table");
+    for i in 0..program.len() {
+        write!(out, " $i{}", i).unwrap();
+    }
+    out.push_str(" $end (local.get $pc)))\n");
+    for (i, op) in program.iter().enumerate() {
+        writeln!(out, "      ;; {}: {}", i, op).unwrap();
+        for line in op_body(op).lines() {
+            writeln!(out, "      {}", line).unwrap();
+        }
+        out.push_str("      )\n");
+    }
+    out.push_str("    )\n");
+    out.push_str("    (if (result i64) (i32.eqz (global.get $sp))\n");
+    out.push_str("      (then (i64.const 0))\n");
+    out.push_str("      (else (call $pop))))\n");
+    out.push_str(")\n");
+    out
+}
+
+// The instructions implementing a single op
+fn op_body(op: &Op) -> String {
+    const A: &str = "(local.set $a (call $pop))\n";
+    const AB: &str = "(local.set $a (call $pop))\n(local.set $b (call $pop))\n";
+    const LABEL: &str = "(local.set $c (call $pop))\n";
+    const JUMP: &str = "(local.set $pc (call $target (local.get $c)))\n(br $dispatch)";
+    let binary = |instr: &str| {
+        format!(
+            "{}(call $push ({} (local.get $a) (local.get $b)))",
+            AB, instr
+        )
+    };
+    let compare = |instr: &str| {
+        format!(
+            "{}(call $push (i64.extend_i32_u ({} (local.get $a) (local.get $b))))",
+            AB, instr
+        )
+    };
+    match op {
+        Op::Lit(x) => format!("(call $push (i64.const {}))", x),
+        Op::MaxInt => format!("(call $push (i64.const {}))", i64::MAX),
+        Op::MinInt => format!("(call $push (i64.const {}))", i64::MIN),
+        Op::Add => binary("i64.add"),
+        Op::Sub => binary("i64.sub"),
+        Op::Mul => binary("i64.mul"),
+        // i64.div_s traps on the one quotient that overflows, which wraps
+        // back to the dividend
+        Op::Div => format!(
+            "{}(call $push (if (result i64) (i32.and \
+             (i64.eq (local.get $a) (i64.const {})) (i64.eq (local.get $b) (i64.const -1)))\n  \
+             (then (local.get $a))\n  \
+             (else (i64.div_s (local.get $a) (local.get $b)))))",
+            AB,
+            i64::MIN
+        ),
+        Op::Inc => format!("{}(call $push (i64.add (local.get $a) (i64.const 1)))", A),
+        Op::Dec => format!("{}(call $push (i64.sub (local.get $a) (i64.const 1)))", A),
+        Op::Dup => format!("{}(call $push (local.get $a))\n(call $push (local.get $a))", A),
+        Op::Swap => format!(
+            "{}(call $push (local.get $a))\n(call $push (local.get $b))",
+            AB
+        ),
+        Op::Tuck => format!(
+            "{}(call $push (local.get $a))\n(call $push (local.get $b))\n(call $push (local.get $a))",
+            AB
+        ),
+        Op::Nip => format!("{}(call $push (local.get $a))", AB),
+        Op::Eq => compare("i64.eq"),
+        Op::Lt => compare("i64.lt_s"),
+        Op::Gt => compare("i64.gt_s"),
+        Op::Select => "(local.set $b (call $pop))\n\
+                       (local.set $a (call $pop))\n\
+                       (local.set $c (call $pop))\n\
+                       (call $push (select (local.get $a) (local.get $b) \
+                       (i64.ne (local.get $c) (i64.const 0))))"
+            .into(),
+        Op::Load => {
+            "(call $push (i64.load (call $addr (call $pop) (global.get $len))))".into()
+        }
+        Op::Store => format!(
+            "{}(if (i64.eq (local.get $a) (i64.extend_i32_u (global.get $len)))\n  \
+             (then\n    \
+             (if (i32.ge_u (global.get $len) (i32.const {})) (then unreachable))\n    \
+             (global.set $len (i32.add (global.get $len) (i32.const 1)))))\n\
+             (i64.store (call $addr (local.get $a) (global.get $len)) (local.get $b))",
+            AB, MEMORY_CELLS
+        ),
+        Op::Put => "(call $put (call $pop))".into(),
+        Op::CR => "(call $cr)".into(),
+        Op::Label(_) | Op::Start => String::new(),
+        Op::Jmp => format!("{}{}", LABEL, JUMP),
+        Op::CJmp | Op::JmpNeg | Op::JmpPos | Op::JmpZero => {
+            let cond = match op {
+                Op::CJmp => "(i64.ne (local.get $a) (i64.const 0))",
+                Op::JmpNeg => "(i64.lt_s (local.get $a) (i64.const 0))",
+                Op::JmpPos => "(i64.gt_s (local.get $a) (i64.const 0))",
+                _ => "(i64.eqz (local.get $a))",
+            };
+            format!("{}{}(if {}\n  (then\n    {}))", LABEL, A, cond, indent(JUMP))
+        }
+        Op::JmpEq | Op::JmpNe | Op::JmpLt | Op::JmpGt => {
+            let cmp = match op {
+                Op::JmpEq => "i64.eq",
+                Op::JmpNe => "i64.ne",
+                Op::JmpLt => "i64.lt_s",
+                _ => "i64.gt_s",
+            };
+            format!(
+                "{}{}(if ({} (local.get $a) (local.get $b))\n  (then\n    {}))",
+                LABEL,
+                AB,
+                cmp,
+                indent(JUMP)
+            )
+        }
+        Op::Halt => "(br $end)".into(),
+        op => format!(";; unsupported op: {}\nunreachable", op.name()),
+    }
+}
+
+// Indents every line after the first to sit inside a `then`
+fn indent(code: &str) -> String {
+    code.replace('\n', "\n    ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lexer;
+
+    #[test]
+    fn arithmetic() {
+        let program = Lexer::codegen("2 3 + .").unwrap();
+        let expected = r#"(module
+  (import "env" "put" (func $put (param i64)))
+  (import "env" "cr" (func $cr))
+  (memory (export "memory") 1)
+  (global $sp (mut i32) (i32.const 0))
+  (global $len (mut i32) (i32.const 0))
+  (func $push (param $x i64)
+    (if (i32.ge_u (global.get $sp) (i32.const 32768)) (then unreachable))
+    (i64.store (global.get $sp) (local.get $x))
+    (global.set $sp (i32.add (global.get $sp) (i32.const 8))))
+  (func $pop (result i64)
+    (if (i32.eqz (global.get $sp)) (then unreachable))
+    (global.set $sp (i32.sub (global.get $sp) (i32.const 8)))
+    (i64.load (global.get $sp)))
+  (func $addr (param $ptr i64) (param $end i32) (result i32)
+    (if (i64.ge_u (local.get $ptr) (i64.extend_i32_u (local.get $end))) (then unreachable))
+    (i32.add (i32.const 32768) (i32.shl (i32.wrap_i64 (local.get $ptr)) (i32.const 3))))
+  (func $target (param $label i64) (result i32)
+    unreachable)
+  (func (export "run") (result i64)
+    (local $pc i32) (local $a i64) (local $b i64) (local $c i64)
+    (local.set $pc (i32.const 0))
+    (loop $dispatch
+      (block $end
+      (block $i3
+      (block $i2
+      (block $i1
+      (block $i0
+        (br_table $i0 $i1 $i2 $i3 $end (local.get $pc)))
+      ;; 0: 2
+      (call $push (i64.const 2))
+      )
+      ;; 1: 3
+      (call $push (i64.const 3))
+      )
+      ;; 2: +
+      (local.set $a (call $pop))
+      (local.set $b (call $pop))
+      (call $push (i64.add (local.get $a) (local.get $b)))
+      )
+      ;; 3: .
+      (call $put (call $pop))
+      )
+    )
+    (if (result i64) (i32.eqz (global.get $sp))
+      (then (i64.const 0))
+      (else (call $pop))))
+)
+"#;
+        assert_eq!(to_wat(&program), expected);
+    }
+
+    #[test]
+    fn jumps() {
+        let program = Lexer::codegen("start 0: 1 jz").unwrap();
+        let wat = to_wat(&program);
+        assert!(wat.contains("(local.set $pc (i32.const 0))"));
+        assert!(wat.contains(
+            "    (if (i64.eq (local.get $label) (i64.const 0)) (then (return (i32.const 1))))\n"
+        ));
+        assert!(wat.contains(
+            "      (if (i64.eqz (local.get $a))\n        (then\n          \
+             (local.set $pc (call $target (local.get $c)))\n          (br $dispatch)))\n"
+        ));
+        // Parentheses balance
+        let depth = wat.chars().fold(0i64, |depth, c| match c {
+            '(' => depth + 1,
+            ')' => depth - 1,
+            _ => depth,
+        });
+        assert_eq!(depth, 0);
+    }
+
+    #[test]
+    fn division() {
+        let program = Lexer::codegen("-1 minint /").unwrap();
+        assert!(to_wat(&program).contains(
+            "      (call $push (if (result i64) (i32.and \
+             (i64.eq (local.get $a) (i64.const -9223372036854775808)) \
+             (i64.eq (local.get $b) (i64.const -1)))\n        \
+             (then (local.get $a))\n        \
+             (else (i64.div_s (local.get $a) (local.get $b)))))\n"
+        ));
+
+        // The same result as the VM when it wraps
+        let mut vm = crate::VM::new();
+        vm.set_overflow_mode(crate::OverflowMode::Wrap);
+        assert_eq!(vm.execute(&program), Ok(Some(i64::MIN)));
+    }
+
+    #[test]
+    fn unsupported_ops() {
+        let program = Lexer::codegen("4 sqrt").unwrap();
+        assert!(to_wat(&program).contains(";; unsupported op: sqrt\n      unreachable\n"));
+    }
+}
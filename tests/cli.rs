@@ -0,0 +1,96 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run_with_stdin(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bytti"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn program_from_stdin() {
+    let output = run_with_stdin(&["-"], "2 3 + .");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "5");
+
+    let output = run_with_stdin(&[], "2 3 * .");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "6");
+}
+
+#[test]
+fn runtime_error_from_stdin() {
+    let output = run_with_stdin(&["-"], "0 1 /");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "1:5: divide by zero\n"
+    );
+}
+
+#[test]
+fn trace_to_file() {
+    let path = std::env::temp_dir().join(format!("bytti-trace-{}", std::process::id()));
+    let path_arg = path.to_str().unwrap();
+
+    let output = run_with_stdin(&["--trace", path_arg, "-"], "2 3 + .");
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "0 2 []\n1 3 [2]\n2 + [2, 3]\n3 . [5]\n"
+    );
+
+    // The trace is written even when the program fails
+    let output = run_with_stdin(&["--trace", path_arg], "0 1 /");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "0 0 []\n1 1 [0]\n2 / [0, 1]\n"
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn exit_code() {
+    let output = run_with_stdin(&["-"], "1 2 . 3 exit");
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "2");
+
+    let output = run_with_stdin(&["-"], "4294967296 exit");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn program_args() {
+    let output = run_with_stdin(&["-", "10", "--trace", "-5"], "0 arg 2 arg + .");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "5");
+}
+
+#[test]
+fn json_errors() {
+    let output = run_with_stdin(&["--error-format=json", "-"], "0 1 /");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "{\"kind\":\"DivideByZero\",\"message\":\"divide by zero\",\"line\":1,\"col\":5}\n"
+    );
+
+    let output = run_with_stdin(&["--error-format=json"], "1\n  \"x\\ +");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "{\"kind\":\"UnknownToken\",\"message\":\"unknown token `\\\"x\\\\`\",\"line\":2,\"col\":3}\n"
+    );
+}
@@ -0,0 +1,34 @@
+use std::process::Command;
+
+// Builds the library on its own with only `alloc`, in a target directory of
+// its own so it does not wait on or disturb the build running this test
+#[test]
+fn builds_without_std() {
+    let output = Command::new(env!("CARGO"))
+        .args(["build", "--lib", "--no-default-features", "--target-dir"])
+        .arg(std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("no_std"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+// The library's own tests also have to build and pass with only `alloc`
+#[test]
+fn lib_tests_without_std() {
+    let output = Command::new(env!("CARGO"))
+        .args(["test", "--lib", "--no-default-features", "--target-dir"])
+        .arg(std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("no_std"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
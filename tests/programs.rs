@@ -0,0 +1,21 @@
+#![cfg(feature = "std")]
+
+use bytti::run_io;
+
+const FIBONACCI: &str = include_str!("fixtures/fibonacci.bytti");
+
+#[test]
+fn fibonacci() {
+    let cases = [
+        (0, 0),
+        (1, 1),
+        (2, 1),
+        (3, 2),
+        (10, 55),
+        (50, 12586269025),
+        (90, 2880067194370816120),
+    ];
+    for (n, fib) in cases {
+        assert_eq!(run_io(FIBONACCI, &[n]), Ok(vec![fib]), "fib({})", n);
+    }
+}